@@ -0,0 +1,61 @@
+use super::*;
+use std::io::Write;
+use tungstenite::protocol::frame::{coding::{Data, OpCode},
+                                   Frame};
+
+impl SimpleSockleClient
+{
+    /// Encodes a string message for sending without writing it yet
+    ///
+    /// Nothing is sent until `flush`, which writes everything queued in one
+    /// go. `write` flushes the queue first so messages stay in order.
+    ///
+    /// Fails with `SendBufferFull` once `SockleClientConfig::max_send_queue`
+    /// messages are waiting.
+    pub fn queue(&mut self, msg: String) -> Result<()>
+    {
+        self.error_if_closed()?;
+        self.error_if_write_closed()?;
+        self.check_writable(msg.len())?;
+        if self.config.max_send_queue.is_some_and(|max| self.queued_count >= max)
+        {
+            return Err(SimpleSockleError::SendBufferFull.into());
+        }
+        let mut frame = Frame::message(msg.into_bytes(), OpCode::Data(Data::Text), true);
+        frame.header_mut().mask = Some(rand::random());
+        frame.format(&mut self.queued)
+             .map_err(SimpleSockleClient::map_error)?;
+        self.queued_count += 1;
+        Ok(())
+    }
+
+    /// Sends everything queued with `queue`
+    pub fn flush(&mut self) -> Result<()>
+    {
+        self.error_if_closed()?;
+        Ok(self.flush_queued()?)
+    }
+
+    pub(crate) fn flush_queued(&mut self) -> Result<(), SimpleSockleError>
+    {
+        if self.queued.is_empty()
+        {
+            return Ok(());
+        }
+        // Frames tungstenite has part written must go out first or the
+        // queued ones would land in the middle of them
+        self.socket
+            .as_mut()
+            .unwrap()
+            .write_pending()
+            .map_err(SimpleSockleClient::map_error)?;
+        self.unflushed = 0;
+        let stream = self.socket.as_mut().unwrap().get_mut();
+        stream.write_all(&self.queued)
+              .and_then(|_| stream.flush())
+              .map_err(|e| SimpleSockleClient::map_error(tungstenite::Error::Io(e)))?;
+        self.queued.clear();
+        self.queued_count = 0;
+        Ok(())
+    }
+}
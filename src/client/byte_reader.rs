@@ -0,0 +1,63 @@
+use super::*;
+use std::io::Read;
+
+/// `std::io::Read` over the payloads of incoming messages, see
+/// `SimpleSockleClient::byte_reader`
+pub struct SockleByteReader<'a>
+{
+    client:  &'a mut SimpleSockleClient,
+    /// Payload of the current message
+    message: Vec<u8>,
+    /// How much of message has been read
+    pos:     usize
+}
+
+impl SimpleSockleClient
+{
+    /// Reads text and binary payloads as one continuous byte stream, for
+    /// decoders that take a `Read`
+    ///
+    /// Message boundaries aren't preserved: payloads are concatenated and a
+    /// read may return the end of one message and never the start of the
+    /// next, use `read_frame` where boundaries matter. Empty messages are
+    /// skipped and pings and pongs handled as usual. The server closing the
+    /// connection reads as end of file, other errors are returned as IO
+    /// errors. Bytes left unread in the current message are lost when the
+    /// reader is dropped.
+    pub fn byte_reader(&mut self) -> SockleByteReader<'_>
+    {
+        SockleByteReader { client:  self,
+                           message: Vec::new(),
+                           pos:     0 }
+    }
+}
+
+impl Read for SockleByteReader<'_>
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        while self.pos == self.message.len()
+        {
+            self.message = match self.client.read_bytes()
+            {
+                Ok(message) => message,
+                Err(e) =>
+                {
+                    let err = e.downcast_ref::<SimpleSockleError>();
+                    if let Some(SimpleSockleError::SocketClosed { .. } | SimpleSockleError::SocketDisconnected) = err
+                    {
+                        return Ok(0);
+                    }
+                    let kind = err.and_then(SimpleSockleError::io_error_kind)
+                                  .unwrap_or(std::io::ErrorKind::Other);
+                    return Err(std::io::Error::new(kind, e));
+                }
+            };
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.message.len() - self.pos);
+        buf[..n].copy_from_slice(&self.message[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
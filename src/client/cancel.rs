@@ -0,0 +1,56 @@
+use super::*;
+use std::sync::{atomic::{AtomicBool, Ordering},
+                Arc};
+
+/// How often a blocking read checks for cancellation once a token exists
+pub(crate) const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Cancels a blocking read from another thread, see
+/// `SimpleSockleClient::read_cancel_token`
+#[derive(Clone, Debug, Default)]
+pub struct ReadCancelToken(Arc<AtomicBool>);
+
+impl ReadCancelToken
+{
+    /// Makes the current or next blocking read return `Cancelled`
+    pub fn cancel(&self)
+    {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool
+    {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl SimpleSockleClient
+{
+    /// Token that interrupts `read`, `read_frame` and `read_bytes`
+    ///
+    /// Once any token has been taken those reads wake up every
+    /// `CANCEL_POLL_INTERVAL` (50ms) to check it. A cancelled read returns
+    /// `SimpleSockleError::Cancelled` and clears the token, the connection
+    /// stays usable. All tokens from the same client are shared.
+    pub fn read_cancel_token(&self) -> ReadCancelToken
+    {
+        self.cancel.clone()
+    }
+
+    /// Whether a token has been handed out, so reads need to poll it
+    pub(crate) fn cancel_watched(&self) -> bool
+    {
+        Arc::strong_count(&self.cancel.0) > 1
+    }
+
+    /// Fails with `Cancelled` if the token was triggered, clearing it
+    pub(crate) fn check_cancelled(&self) -> Result<(), SimpleSockleError>
+    {
+        if self.cancel.0.swap(false, Ordering::SeqCst)
+        {
+            log::debug!("Read cancelled");
+            return Err(SimpleSockleError::Cancelled);
+        }
+        Ok(())
+    }
+}
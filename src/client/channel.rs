@@ -0,0 +1,28 @@
+use super::*;
+use std::sync::mpsc::Receiver;
+
+impl SimpleSockleClient
+{
+    /// Moves the client onto a reader thread that forwards each message
+    /// into the returned channel
+    ///
+    /// The thread ends after forwarding the first error, which includes the
+    /// connection closing. Dropping the receiver ends it once the next
+    /// message arrives. Use `split` first to keep writing.
+    pub fn into_channel(mut self) -> Result<Receiver<Result<String>>>
+    {
+        self.error_if_closed()?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::Builder::new().name("Sockle Client Reader".to_string())
+                                   .spawn(move || loop
+                                   {
+                                       let message = self.read();
+                                       let ended = message.is_err();
+                                       if sender.send(message).is_err() || ended
+                                       {
+                                           break;
+                                       }
+                                   })?;
+        Ok(receiver)
+    }
+}
@@ -0,0 +1,71 @@
+use super::*;
+
+/// Where the client is in the closing handshake, see
+/// `SimpleSockleClient::ready_state`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadyState
+{
+    /// Connected, reads and writes allowed
+    Open,
+    /// Close frame sent by `close_write`, reads allowed until the server's
+    /// close arrives
+    Closing,
+    /// Not connected
+    Closed
+}
+
+impl SimpleSockleClient
+{
+    /// Current state of the connection
+    pub fn ready_state(&self) -> ReadyState
+    {
+        match self.socket.as_ref()
+        {
+            None => ReadyState::Closed,
+            Some(s) if s.can_write() => ReadyState::Open,
+            Some(_) => ReadyState::Closing
+        }
+    }
+
+    /// Sends a close frame but keeps reading, for when the client is done
+    /// sending and still expects replies
+    ///
+    /// Messages queued with `queue` are flushed first. Writes afterwards
+    /// fail with `WriteClosed`. Reads return whatever the server sends
+    /// until it answers the close, that read then fails with
+    /// `SocketClosed` and the client is closed. `close` finishes the
+    /// handshake straight away instead. Does nothing if already closing.
+    ///
+    /// WebSocket has no true half-close: a server may reply to the close
+    /// as soon as it reads it, only what it sent before then arrives.
+    pub fn close_write(&mut self) -> Result<()>
+    {
+        match self.ready_state()
+        {
+            ReadyState::Open =>
+            {}
+            ReadyState::Closing => return Ok(()),
+            ReadyState::Closed => return Err(SimpleSockleError::SocketDisconnected.into())
+        }
+        self.flush_queued()?;
+        log::debug!("Sending close frame, leaving read side open");
+        let cf = CloseFrame { code:   CloseCode::Normal,
+                              reason: "Client finished sending".into() };
+        match self.socket.as_mut().unwrap().close(Some(cf))
+        {
+            // Queued, goes out with the next read
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            r => Ok(r.map_err(SimpleSockleClient::map_error)?)
+        }
+    }
+
+    /// Fails with `WriteClosed` once `close_write` has been called
+    pub(crate) fn error_if_write_closed(&self) -> Result<(), SimpleSockleError>
+    {
+        match self.ready_state()
+        {
+            ReadyState::Closing => Err(SimpleSockleError::WriteClosed),
+            _ => Ok(())
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use super::*;
+use crate::CodecError;
+
+impl SimpleSockleClient
+{
+    /// Encodes value with encode and writes it as a text message
+    ///
+    /// The encoder is any function turning a value into text, e.g.
+    /// `serde_json::to_string`. Encoding failures are returned as
+    /// `SimpleSockleError::Codec` and nothing is sent.
+    ///
+    /// Stands in for a `serde` gated `write_json`/`read_json` pair, as the
+    /// crate doesn't depend on serde_json. Those would be thin wrappers
+    /// over this and `read_decoded`, `write_json<T: Serialize>(&mut self,
+    /// value: &T)` and `read_json<T: DeserializeOwned>(&mut self)`.
+    pub fn write_encoded<T, E, F>(&mut self, value: &T, encode: F) -> Result<()>
+        where F: FnOnce(&T) -> Result<String, E>,
+              E: Into<CodecError>
+    {
+        let msg = encode(value).map_err(|e| SimpleSockleError::Codec(e.into()))?;
+        self.write(msg)
+    }
+
+    /// Reads the next text message and decodes it with decode, e.g.
+    /// `serde_json::from_str`
+    ///
+    /// Decoding failures are returned as `SimpleSockleError::Codec`, the
+    /// message is consumed either way.
+    pub fn read_decoded<T, E, F>(&mut self, decode: F) -> Result<T>
+        where F: FnOnce(&str) -> Result<T, E>,
+              E: Into<CodecError>
+    {
+        let msg = self.read()?;
+        Ok(decode(&msg).map_err(|e| SimpleSockleError::Codec(e.into()))?)
+    }
+}
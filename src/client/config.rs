@@ -0,0 +1,93 @@
+use super::*;
+use tungstenite::protocol::WebSocketConfig;
+
+/// Settings for `SimpleSockleClient`, see `SimpleSockleClient::with_config`
+///
+/// Applied each time the client connects. Compression (permessage-deflate)
+/// isn't offered and the internal read and write buffer sizes can't be set,
+/// the underlying websocket library doesn't support either.
+#[derive(Clone)]
+pub struct SockleClientConfig
+{
+    /// Limit on establishing the TCP connection and on each read/write of
+    /// the TLS and websocket handshakes, None waits for the OS to give up
+    pub connect_timeout:          Option<Duration>,
+    /// TLS settings used for wss urls, None verifies certificates against
+    /// the system roots
+    pub tls:                      Option<TlsConfig>,
+    /// Largest incoming message allowed, defaults to 64MiB, None for no limit
+    pub max_message_size:         Option<usize>,
+    /// Largest outgoing message allowed, None for no limit
+    ///
+    /// WebSocket has no way to negotiate sizes, set it to what the server
+    /// accepts so oversized writes fail with `MessageTooLarge` up front
+    /// rather than the server dropping the connection.
+    pub max_write_size:           Option<usize>,
+    /// Sets TCP_NODELAY when connecting, disabling Nagle's algorithm,
+    /// defaults to true
+    pub nodelay:                  bool,
+    /// Pings the server on an interval and tracks whether it is still
+    /// answering, see `SimpleSockleClient::is_alive`. None to disable
+    pub heartbeat:                Option<HeartbeatConfig>,
+    /// Extra headers sent with the upgrade request, e.g. Origin or
+    /// Authorization
+    ///
+    /// Authorization, Cookie and Proxy-Authorization are left out when a
+    /// redirect leads to another scheme, host or port.
+    pub headers:                  Vec<(String, String)>,
+    /// Messages allowed to wait unsent, None for no limit
+    ///
+    /// Writes beyond this fail with `SendBufferFull` instead of buffering
+    /// more. Only reachable when writes can't complete straight away, i.e.
+    /// with a write timeout or non-blocking socket. Applies separately to
+    /// messages waiting for `flush`.
+    pub max_send_queue:           Option<usize>,
+    /// Skips text messages that aren't valid UTF-8 instead of failing the
+    /// read with `SocketError(Utf8)`, defaults to false
+    ///
+    /// Skipped messages are logged. They can't be decoded lossily instead,
+    /// tungstenite validates text as it reads and discards the payload of
+    /// invalid messages. Only works for messages sent in a single frame, an
+    /// invalid fragmented message still breaks the connection.
+    pub skip_invalid_utf8:        bool,
+    /// Redirects answering the handshake to follow, defaults to 0
+    ///
+    /// Each Location is connected to in turn, http and https urls are
+    /// treated as ws and wss. Once exhausted, or with 0, connecting fails
+    /// with `Redirected`. Only applies to direct connections, not through
+    /// a proxy.
+    pub max_redirects:            usize,
+    /// Follow redirects from wss to ws, defaults to false
+    ///
+    /// Off, such a redirect fails with `RedirectRejected` rather than
+    /// silently dropping TLS.
+    pub allow_insecure_redirects: bool
+}
+
+impl Default for SockleClientConfig
+{
+    fn default() -> Self
+    {
+        Self { connect_timeout:          None,
+               tls:                      None,
+               max_message_size:         Some(64 << 20),
+               max_write_size:           None,
+               nodelay:                  true,
+               heartbeat:                None,
+               headers:                  Vec::new(),
+               max_send_queue:           None,
+               skip_invalid_utf8:        false,
+               max_redirects:            0,
+               allow_insecure_redirects: false }
+    }
+}
+
+impl SockleClientConfig
+{
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig
+    {
+        WebSocketConfig { max_message_size: self.max_message_size,
+                          max_send_queue:   self.max_send_queue,
+                          ..Default::default() }
+    }
+}
@@ -0,0 +1,186 @@
+use super::*;
+use cancel::CANCEL_POLL_INTERVAL;
+use tungstenite::Error;
+
+/// Heartbeat settings, see `SockleClientConfig::heartbeat`
+#[derive(Clone, Copy, Debug)]
+pub struct HeartbeatConfig
+{
+    /// Sends a ping when this long has passed since the last one
+    pub interval: Duration,
+    /// The connection counts as dead once nothing has been received for this
+    /// long, should be a few intervals
+    pub timeout:  Duration
+}
+
+/// Liveness tracking for a connected client
+pub(crate) struct Heartbeat
+{
+    config:    HeartbeatConfig,
+    last_seen: Instant,
+    last_ping: Instant
+}
+
+impl Heartbeat
+{
+    pub(crate) fn new(config: HeartbeatConfig) -> Self
+    {
+        let now = Instant::now();
+        Self { config,
+               last_seen: now,
+               last_ping: now }
+    }
+
+    /// Records traffic from the server
+    pub(crate) fn seen(&mut self)
+    {
+        self.last_seen = Instant::now();
+    }
+
+    /// Time left until the next ping is due
+    fn until_ping(&self) -> Duration
+    {
+        (self.last_ping + self.config.interval).saturating_duration_since(Instant::now())
+    }
+
+    fn is_alive(&self) -> bool
+    {
+        self.last_seen.elapsed() < self.config.timeout
+    }
+}
+
+impl SimpleSockleClient
+{
+    /// Starts a heartbeat on the current and any later connections
+    ///
+    /// Pings are sent from the client's own calls rather than a separate
+    /// thread: blocking `read` and `read_frame` wake up to send them, other
+    /// calls send one if it is due. Pongs are flushed as soon as a ping is
+    /// read.
+    pub fn start_heartbeat(&mut self, config: HeartbeatConfig)
+    {
+        self.config.heartbeat = Some(config);
+        if self.socket.is_some()
+        {
+            self.heartbeat = Some(Heartbeat::new(config));
+        }
+    }
+
+    /// Cancels the heartbeat, `is_alive` then only reflects whether the
+    /// client is connected
+    pub fn stop_heartbeat(&mut self)
+    {
+        self.config.heartbeat = None;
+        self.heartbeat = None;
+    }
+
+    /// Whether the client is connected and, with a heartbeat running, has
+    /// heard from the server within the heartbeat timeout
+    pub fn is_alive(&self) -> bool
+    {
+        self.socket.is_some() && self.heartbeat.as_ref().is_none_or(Heartbeat::is_alive)
+    }
+
+    /// Pings the server and waits for the pong, confirming the connection
+    /// is live end to end
+    ///
+    /// Fails with a `TimedOut` IO error if no pong arrives within timeout.
+    /// Text messages received meanwhile are kept for the next reads, other
+    /// frames are discarded.
+    pub fn wait_ready(&mut self, timeout: Duration) -> Result<(), SimpleSockleError>
+    {
+        use std::io::ErrorKind::{TimedOut, WouldBlock};
+
+        self.error_if_closed()?;
+        let payload = rand::random::<u64>().to_be_bytes().to_vec();
+        self.socket
+            .as_mut()
+            .unwrap()
+            .write_message(Message::Ping(payload.clone()))
+            .map_err(SimpleSockleClient::map_error)?;
+        self.set_non_blocking(false)?;
+        let deadline = Instant::now() + timeout;
+        let result = loop
+        {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero()
+            {
+                break Err(SimpleSockleError::IoError(std::io::Error::new(TimedOut, "No pong before timeout")));
+            }
+            self.set_timeout(Some(remaining))?;
+            match self.read_any_frame()
+            {
+                Ok(SockleFrame::Pong(p)) if p == payload => break Ok(()),
+                Ok(SockleFrame::Text(t)) => self.peeked.push_back(t),
+                Ok(_) =>
+                {}
+                Err(e) if matches!(e.io_error_kind(), Some(WouldBlock | TimedOut)) =>
+                {}
+                Err(e) => return Err(e)
+            }
+        };
+        self.set_timeout(None)?;
+        result
+    }
+
+    /// Sends a heartbeat ping if one is due
+    pub(crate) fn heartbeat_tick(&mut self) -> Result<(), SimpleSockleError>
+    {
+        match self.heartbeat.as_mut()
+        {
+            // No pings once closing, the server's close is all that's left
+            Some(_) if self.socket.as_ref().is_some_and(|s| !s.can_write()) => return Ok(()),
+            Some(h) if h.until_ping().is_zero() => h.last_ping = Instant::now(),
+            _ => return Ok(())
+        }
+        log::debug!("Sending heartbeat ping");
+        match self.socket.as_mut().unwrap().write_message(Message::Ping(vec![0]))
+        {
+            // Queued, goes out with the next write or read
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            r => r.map_err(SimpleSockleClient::map_error)
+        }
+    }
+
+    /// Reads the next frame, waking up to send heartbeat pings and check
+    /// for cancellation while waiting
+    pub(crate) fn read_any_frame_blocking(&mut self) -> Result<SockleFrame, SimpleSockleError>
+    {
+        let result = self.read_any_frame_waking();
+        // Whichever way the read ended, including cancelled, later reads
+        // mustn't inherit the short wake up timeout
+        let restored = match self.socket
+        {
+            Some(_) => self.set_timeout(None),
+            None => Ok(())
+        };
+        let frame = result?;
+        restored?;
+        Ok(frame)
+    }
+
+    fn read_any_frame_waking(&mut self) -> Result<SockleFrame, SimpleSockleError>
+    {
+        use std::io::ErrorKind::{TimedOut, WouldBlock};
+
+        loop
+        {
+            self.check_cancelled()?;
+            self.heartbeat_tick()?;
+            let ping_wait = self.heartbeat.as_ref().map(Heartbeat::until_ping);
+            let cancel_wait = self.cancel_watched().then_some(CANCEL_POLL_INTERVAL);
+            let wait = match ping_wait.into_iter().chain(cancel_wait).min()
+            {
+                Some(wait) => wait.max(Duration::from_millis(1)),
+                None => return self.read_any_frame()
+            };
+            self.set_timeout(Some(wait))?;
+            match self.read_any_frame()
+            {
+                Err(e) if matches!(e.io_error_kind(), Some(WouldBlock | TimedOut)) =>
+                {}
+                result => return result
+            }
+        }
+    }
+}
@@ -1,12 +1,47 @@
 use anyhow::Result;
-use std::time::Duration;
-use tungstenite::{protocol::CloseFrame, Message};
+use std::time::{Duration, Instant};
+use tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame},
+                  Message};
 use url::Url;
 
+mod batch;
+mod byte_reader;
+mod cancel;
+mod codec;
+mod channel;
+mod close;
+mod config;
+mod heartbeat;
+mod proxy;
+mod reconnect;
+mod redirect;
 mod simple_sockle_client;
+mod split;
+mod tls;
 
-use crate::SimpleSockleError;
-pub use simple_sockle_client::SimpleSockleClient;
+pub use byte_reader::SockleByteReader;
+pub use cancel::ReadCancelToken;
+pub use close::ReadyState;
+use crate::{SimpleSockleError, SockleClose, SockleTransport};
+pub use config::SockleClientConfig;
+pub use heartbeat::HeartbeatConfig;
+use heartbeat::Heartbeat;
+use reconnect::OnReconnectFn;
+use redirect::CREDENTIAL_HEADERS;
+pub use proxy::{ProxyConfig, ProxyCredentials, ProxyKind};
+pub use simple_sockle_client::{ClientStream, SimpleSockleClient};
+pub use split::{SockleReader, SockleWriter, SplitStream};
+pub use tls::TlsConfig;
+
+/// A frame read by `read_frame`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SockleFrame
+{
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>)
+}
 
 pub trait SockleClient
 {
@@ -15,16 +50,141 @@ pub trait SockleClient
     /// Can be called to reconnect if closed.
     /// Use close method to ensure it's closed first.
     fn connect(&mut self, url: &str) -> Result<()>;
+    /// Connects, retrying up to attempts times in total with exponential
+    /// backoff starting at base_delay
+    ///
+    /// Only retries errors that `SimpleSockleError::is_recoverable`
+    /// considers worth it, such as connection refused or timeouts. Returns
+    /// the last error once attempts are used up.
+    fn connect_with_retry(&mut self, url: &str, attempts: u32, base_delay: Duration) -> Result<()>
+    {
+        let mut delay = base_delay;
+        let mut attempt = 1;
+        loop
+        {
+            match self.connect(url)
+            {
+                Err(e) if attempt < attempts
+                          && e.downcast_ref::<SimpleSockleError>()
+                              .is_some_and(SimpleSockleError::is_recoverable) =>
+                {
+                    log::warn!("Connect attempt {attempt} of {attempts} failed, retrying in {delay:?}: {e}");
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                    attempt += 1;
+                }
+                result => return result
+            }
+        }
+    }
     /// Writes a string message to the socket
     fn write(&mut self, msg: String) -> Result<()>;
+    /// Writes a string message and waits until it has been fully handed to
+    /// the OS, failing with a `TimedOut` IO error if that takes longer than
+    /// timeout
+    ///
+    /// On timeout the message stays queued and is sent by later calls. A
+    /// zero timeout makes a single non-blocking attempt.
+    fn write_flush(&mut self, msg: String, timeout: Duration) -> Result<()>;
     /// Reads if possible, return Ok(None) if not
     fn try_read(&mut self) -> Result<Option<String>>;
+    /// Reads a message if possible without consuming it, Ok(None) if there
+    /// isn't one
+    ///
+    /// The next read of any kind returns the peeked message first.
+    fn peek(&mut self) -> Result<Option<&str>>;
     /// Reads and blocks until a message is returned
     fn read(&mut self) -> Result<String>;
+    /// Reads and blocks until a message is returned, replacing the contents
+    /// of buf with it
+    ///
+    /// tungstenite decodes every message into a `String` of its own, so this
+    /// can't avoid that allocation. It saves keeping a second one around:
+    /// the message is copied into buf when it has room, otherwise buf takes
+    /// over the message's allocation.
+    fn read_into(&mut self, buf: &mut String) -> Result<()>
+    {
+        let msg = self.read()?;
+        if buf.capacity() >= msg.len()
+        {
+            buf.clear();
+            buf.push_str(&msg);
+        }
+        else
+        {
+            *buf = msg;
+        }
+        Ok(())
+    }
+    /// Reads and blocks until any frame is returned, including pings and pongs
+    ///
+    /// Pings are still answered automatically.
+    fn read_frame(&mut self) -> Result<SockleFrame>;
+    /// Reads and blocks until a text or binary message is returned, as bytes
+    ///
+    /// Text frames are still UTF-8 validated by tungstenite before they get
+    /// here, so invalid text fails with `SocketError(Utf8)`, or is skipped
+    /// with `SockleClientConfig::skip_invalid_utf8`. The frame is dropped
+    /// but the connection stays usable.
+    fn read_bytes(&mut self) -> Result<Vec<u8>>;
     /// Reads and blocks for timeout period, returning Ok(None) on timeout
+    ///
+    /// Ok(None) always means the full timeout elapsed, early wakeups from
+    /// the OS are retried for the time remaining. A zero timeout returns
+    /// Ok(None) straight away.
     fn read_timeout(&mut self, timeout: Duration) -> Result<Option<String>>;
+    /// Reads and blocks until deadline, returning Ok(None) if it passes
+    ///
+    /// Returns Ok(None) immediately if the deadline has already passed.
+    fn read_deadline(&mut self, deadline: Instant) -> Result<Option<String>>;
+    /// Collects every message arriving within window, blocking for all of
+    /// it
+    ///
+    /// Returns early only if the connection closes or fails, with the
+    /// messages read until then. The error is returned instead when nothing
+    /// was read, otherwise it is logged and left to later reads to hit.
+    fn read_batch(&mut self, window: Duration) -> Result<Vec<String>>
+    {
+        let deadline = Instant::now() + window;
+        let mut batch = Vec::new();
+        loop
+        {
+            match self.read_deadline(deadline)
+            {
+                Ok(Some(m)) => batch.push(m),
+                Ok(None) => return Ok(batch),
+                Err(e) if batch.is_empty() => return Err(e),
+                Err(e) =>
+                {
+                    log::debug!("Batch ended early: {e}");
+                    return Ok(batch);
+                }
+            }
+        }
+    }
+    /// Writes msg then reads the next text message, returning Ok(None) if
+    /// none arrives within timeout
+    ///
+    /// Assumes a strict request/response server: anything the server sends
+    /// unprompted in between is returned as the reply.
+    fn request(&mut self, msg: String, timeout: Duration) -> Result<Option<String>>
+    {
+        self.write(msg)?;
+        self.read_timeout(timeout)
+    }
     /// Closes the socket connection, returns Ok(()) if already closed
-    fn close(&mut self) -> Result<()>;
+    fn close(&mut self) -> Result<()>
+    {
+        self.close_with(CloseCode::Normal, "Client requested close".to_string())
+    }
+    /// Closes the socket connection sending the given code and reason,
+    /// returns Ok(()) if already closed
+    fn close_with(&mut self, code: CloseCode, reason: String) -> Result<()>;
+    /// Same as close_with, e.g. `client.close_as(SockleClose::policy("Bye"))`
+    fn close_as(&mut self, close: SockleClose) -> Result<()>
+    {
+        self.close_with(close.code, close.reason)
+    }
     /// Sends a ping
     fn ping(&mut self) -> Result<()>;
 }
@@ -33,36 +193,79 @@ impl SockleClient for SimpleSockleClient
 {
     fn connect(&mut self, url: &str) -> Result<()>
     {
-        log::info!("Connecting socket ({url})");
-
-        if self.error_if_closed().is_ok()
-        {
-            return Err(SimpleSockleError::SocketConnected.into());
-        }
-
         let url = Url::parse(url).map_err(|e| SimpleSockleError::InvalidUrl(e.to_string()))?;
-
-        let socket = tungstenite::connect(url).map_err(SimpleSockleClient::map_error)?
-                                              .0;
-        self.socket = Some(socket);
-
-        log::info!("Connected");
-        Ok(())
+        self.connect_url(url)
     }
 
     fn write(&mut self, msg: String) -> Result<()>
     {
         self.error_if_closed()?;
-        Ok(self.socket
-               .as_mut()
-               .unwrap()
-               .write_message(Message::Text(msg))
-               .map_err(SimpleSockleClient::map_error)?)
+        self.check_writable(msg.len())?;
+        self.heartbeat_tick()?;
+        self.flush_queued()?;
+        let len = msg.len();
+        let result = self.socket.as_mut().unwrap().write_message(Message::Text(msg));
+        self.track_write(len, &result);
+        Ok(result.map_err(SimpleSockleClient::map_error)?)
+    }
+
+    fn write_flush(&mut self, msg: String, timeout: Duration) -> Result<()>
+    {
+        self.error_if_closed()?;
+        self.check_writable(msg.len())?;
+        self.heartbeat_tick()?;
+        self.flush_queued()?;
+
+        use std::io::ErrorKind::{TimedOut, WouldBlock};
+        let deadline = Instant::now() + timeout;
+        // A zero write timeout is rejected by the OS, so try once without blocking
+        if timeout.is_zero()
+        {
+            self.set_non_blocking(true)?;
+        }
+        else
+        {
+            self.set_write_timeout(Some(timeout))?;
+        }
+        let len = msg.len();
+        let socket = self.socket.as_mut().unwrap();
+        let mut result = socket.write_message(Message::Text(msg));
+        loop
+        {
+            match result
+            {
+                Err(tungstenite::Error::Io(ref e)) if matches!(e.kind(), WouldBlock | TimedOut) =>
+                {
+                    if Instant::now() >= deadline
+                    {
+                        result = Err(std::io::Error::new(TimedOut, "Message not flushed before timeout").into());
+                        break;
+                    }
+                    result = socket.write_pending();
+                }
+                _ => break
+            }
+        }
+        self.track_write(len, &result);
+        if timeout.is_zero()
+        {
+            self.set_non_blocking(false)?;
+        }
+        else
+        {
+            self.set_write_timeout(None)?;
+        }
+        Ok(result.map_err(SimpleSockleClient::map_error)?)
     }
 
     fn try_read(&mut self) -> Result<Option<String>>
     {
+        if let Some(m) = self.peeked.pop_front()
+        {
+            return Ok(Some(m));
+        }
         self.error_if_closed()?;
+        self.heartbeat_tick()?;
         self.set_non_blocking(true)?;
 
         let result = self.read_and_wrap_by_error_kind(|x| x == std::io::ErrorKind::WouldBlock);
@@ -74,29 +277,115 @@ impl SockleClient for SimpleSockleClient
         Ok(result?)
     }
 
+    fn peek(&mut self) -> Result<Option<&str>>
+    {
+        if self.peeked.is_empty()
+        {
+            if let Some(m) = self.try_read()?
+            {
+                self.peeked.push_back(m);
+            }
+        }
+        Ok(self.peeked.front().map(String::as_str))
+    }
+
     fn read(&mut self) -> Result<String>
     {
+        if let Some(m) = self.peeked.pop_front()
+        {
+            return Ok(m);
+        }
         self.error_if_closed()?;
 
-        Ok(self.read_message()?)
+        Ok(self.read_message_with(SimpleSockleClient::read_any_frame_blocking)?)
+    }
+
+    fn read_frame(&mut self) -> Result<SockleFrame>
+    {
+        if let Some(m) = self.peeked.pop_front()
+        {
+            return Ok(SockleFrame::Text(m));
+        }
+        self.error_if_closed()?;
+
+        Ok(self.read_any_frame_blocking()?)
+    }
+
+    fn read_bytes(&mut self) -> Result<Vec<u8>>
+    {
+        if let Some(m) = self.peeked.pop_front()
+        {
+            return Ok(m.into_bytes());
+        }
+        self.error_if_closed()?;
+
+        loop
+        {
+            match self.read_any_frame_blocking()?
+            {
+                SockleFrame::Text(t) => return Ok(t.into_bytes()),
+                SockleFrame::Binary(b) => return Ok(b),
+                SockleFrame::Ping(_) | SockleFrame::Pong(_) =>
+                {}
+            }
+        }
     }
 
     fn read_timeout(&mut self, timeout: Duration) -> Result<Option<String>>
     {
+        if let Some(m) = self.peeked.pop_front()
+        {
+            return Ok(Some(m));
+        }
         self.error_if_closed()?;
-        self.set_timeout(Some(timeout))?;
+        self.heartbeat_tick()?;
+        self.set_non_blocking(false)?;
 
-        // Unix returns WouldBlock, windows returns TimedOut
+        // Unix returns WouldBlock, windows returns TimedOut. Either can come
+        // back before the timeout is up, so only give up once it has passed
         use std::io::ErrorKind::{TimedOut, WouldBlock};
-        let result = self.read_and_wrap_by_error_kind(|x| matches!(x, WouldBlock | TimedOut));
-        if result.is_ok()
+        let deadline = Instant::now() + timeout;
+        loop
         {
-            self.set_timeout(None)?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero()
+            {
+                self.set_timeout(None)?;
+                return Ok(None);
+            }
+            self.set_timeout(Some(remaining))?;
+            match self.read_and_wrap_by_error_kind(|x| matches!(x, WouldBlock | TimedOut))
+            {
+                Ok(None) =>
+                {}
+                result =>
+                {
+                    if result.is_ok()
+                    {
+                        self.set_timeout(None)?;
+                    }
+                    return Ok(result?);
+                }
+            }
         }
-        Ok(result?)
     }
 
-    fn close(&mut self) -> Result<()>
+    fn read_deadline(&mut self, deadline: Instant) -> Result<Option<String>>
+    {
+        let now = Instant::now();
+        if deadline <= now
+        {
+            if let Some(m) = self.peeked.pop_front()
+            {
+                return Ok(Some(m));
+            }
+            self.error_if_closed()?;
+            return Ok(None);
+        }
+        self.read_timeout(deadline - now)
+    }
+
+    fn close_with(&mut self, code: CloseCode, reason: String) -> Result<()>
     {
         if self.error_if_closed().is_err()
         {
@@ -104,13 +393,11 @@ impl SockleClient for SimpleSockleClient
             return Ok(());
         }
         log::info!("Closing socket");
-        let result = self.close_socket(Some(CloseFrame {
-            code: tungstenite::protocol::frame::coding::CloseCode::Normal,
-            reason: "Client requested close".into(),
-        }))?;
+        self.close_socket(Some(CloseFrame { code,
+                                            reason: reason.into() }))?;
         log::info!("Socket Closed");
 
-        Ok(result)
+        Ok(())
     }
 
     fn ping(&mut self) -> Result<()>
@@ -0,0 +1,235 @@
+use super::*;
+use std::{io::{Read, Write},
+          net::{IpAddr, TcpStream}};
+
+/// Protocol used to tunnel through a proxy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyKind
+{
+    /// HTTP proxy using the CONNECT method
+    Http,
+    /// SOCKS5 proxy
+    Socks5
+}
+
+/// Username/password used to authenticate with a proxy
+#[derive(Clone, Debug)]
+pub struct ProxyCredentials
+{
+    pub username: String,
+    pub password: String
+}
+
+/// Proxy used by `connect_via_proxy` to reach the target host
+#[derive(Clone, Debug)]
+pub struct ProxyConfig
+{
+    pub kind:        ProxyKind,
+    /// Proxy address as host:port
+    pub address:     String,
+    pub credentials: Option<ProxyCredentials>
+}
+
+impl ProxyConfig
+{
+    pub fn http(address: &str) -> Self
+    {
+        Self { kind:        ProxyKind::Http,
+               address:     address.to_string(),
+               credentials: None }
+    }
+
+    pub fn socks5(address: &str) -> Self
+    {
+        Self { kind:        ProxyKind::Socks5,
+               address:     address.to_string(),
+               credentials: None }
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self
+    {
+        self.credentials = Some(ProxyCredentials { username: username.to_string(),
+                                                   password: password.to_string() });
+        self
+    }
+
+    /// Opens a TCP connection to the proxy and tunnels it through to host:port
+    pub(crate) fn open_tunnel(&self,
+                              host: &str,
+                              port: u16,
+                              timeout: Option<Duration>)
+                              -> Result<TcpStream, SimpleSockleError>
+    {
+        log::debug!("Opening {:?} proxy tunnel via {} to {host}:{port}", self.kind, self.address);
+        let mut stream = SimpleSockleClient::connect_tcp(self.address.as_str(), timeout)?;
+        match self.kind
+        {
+            ProxyKind::Http => self.http_connect(&mut stream, host, port)?,
+            ProxyKind::Socks5 => self.socks5_connect(&mut stream, host, port)?
+        }
+        Ok(stream)
+    }
+
+    fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), SimpleSockleError>
+    {
+        // IPv6 addresses are bracketed in an authority, like in a url
+        let authority = match host.parse::<IpAddr>()
+        {
+            Ok(IpAddr::V6(ip)) => format!("[{ip}]:{port}"),
+            _ => format!("{host}:{port}")
+        };
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some(c) = self.credentials.as_ref()
+        {
+            let token = base64::encode(format!("{}:{}", c.username, c.password));
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())
+              .map_err(SimpleSockleError::IoError)?;
+
+        // Read byte by byte so nothing past the proxy response is consumed
+        let mut response = Vec::new();
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n")
+        {
+            if response.len() > 8192
+            {
+                return Err(SimpleSockleError::ProxyError("Proxy response too large".to_string()));
+            }
+            match stream.read(&mut byte).map_err(SimpleSockleError::IoError)?
+            {
+                0 => return Err(SimpleSockleError::ProxyError("Proxy closed connection".to_string())),
+                _ => response.push(byte[0])
+            }
+        }
+
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        match status_line.split_whitespace().nth(1)
+        {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(SimpleSockleError::ProxyError(format!("Proxy refused CONNECT: {status_line}")))
+        }
+    }
+
+    fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<(), SimpleSockleError>
+    {
+        let io = SimpleSockleError::IoError;
+        let method = if self.credentials.is_some() { 0x02 } else { 0x00 };
+        stream.write_all(&[0x05, 0x01, method]).map_err(io)?;
+
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).map_err(io)?;
+        ProxyConfig::check_socks5_version(reply[0])?;
+        if reply[1] != method
+        {
+            return Err(SimpleSockleError::ProxyError("SOCKS5 proxy rejected authentication method".to_string()));
+        }
+
+        if let Some(c) = self.credentials.as_ref()
+        {
+            if c.username.len() > 255 || c.password.len() > 255
+            {
+                return Err(SimpleSockleError::ProxyError("SOCKS5 credentials too long".to_string()));
+            }
+            let mut auth = vec![0x01, c.username.len() as u8];
+            auth.extend_from_slice(c.username.as_bytes());
+            auth.push(c.password.len() as u8);
+            auth.extend_from_slice(c.password.as_bytes());
+            stream.write_all(&auth).map_err(io)?;
+            stream.read_exact(&mut reply).map_err(io)?;
+            if reply[1] != 0x00
+            {
+                return Err(SimpleSockleError::ProxyError("SOCKS5 authentication failed".to_string()));
+            }
+        }
+
+        let mut request = vec![0x05, 0x01, 0x00];
+        match host.parse::<IpAddr>()
+        {
+            Ok(IpAddr::V4(ip)) =>
+            {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) =>
+            {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) =>
+            {
+                if host.len() > 255
+                {
+                    return Err(SimpleSockleError::ProxyError("SOCKS5 host name too long".to_string()));
+                }
+                request.push(0x03);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).map_err(io)?;
+
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).map_err(io)?;
+        ProxyConfig::check_socks5_version(header[0])?;
+        if header[1] != 0x00
+        {
+            return Err(SimpleSockleError::ProxyError(format!("SOCKS5 connect failed with code {}", header[1])));
+        }
+        // Discard the bound address, its length depends on the address type
+        let remaining = match header[3]
+        {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 =>
+            {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).map_err(io)?;
+                len[0] as usize
+            }
+            t => return Err(SimpleSockleError::ProxyError(format!("SOCKS5 unknown address type {t}")))
+        };
+        let mut bound = vec![0u8; remaining + 2];
+        stream.read_exact(&mut bound).map_err(io)?;
+        Ok(())
+    }
+
+    /// Fails unless a reply came from a SOCKS5 server
+    fn check_socks5_version(version: u8) -> Result<(), SimpleSockleError>
+    {
+        match version
+        {
+            0x05 => Ok(()),
+            v => Err(SimpleSockleError::ProxyError(format!("Proxy replied with SOCKS version {v}, expected 5")))
+        }
+    }
+}
+
+impl SimpleSockleClient
+{
+    /// Connects socket to url, tunnelling through the given proxy
+    ///
+    /// The WebSocket (and TLS for wss) handshake runs over the tunnel.
+    pub fn connect_via_proxy(&mut self, url: &str, proxy: ProxyConfig) -> Result<()>
+    {
+        log::info!("Connecting socket ({url}) via proxy ({})", proxy.address);
+
+        if self.error_if_closed().is_ok()
+        {
+            return Err(SimpleSockleError::SocketConnected.into());
+        }
+
+        let url = Url::parse(url).map_err(|e| SimpleSockleError::InvalidUrl(e.to_string()))?;
+        let (host, port) = SimpleSockleClient::host_and_port(&url)?;
+
+        let stream = proxy.open_tunnel(&host, port, self.config.connect_timeout)?;
+        self.handshake(url, Box::new(stream), None, true)?;
+        self.connected()?;
+
+        log::info!("Connected");
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+use super::*;
+use simple_sockle_client::CLOSE_TIMEOUT;
+use std::io::Read;
+
+/// Hook run after each reconnect, see `SimpleSockleClient::set_on_reconnect`
+pub(crate) type OnReconnectFn = Box<dyn FnMut(&mut SimpleSockleClient) -> Result<()> + Send>;
+
+impl SimpleSockleClient
+{
+    /// Runs f after every successful connect except the first, before
+    /// `connect` returns
+    ///
+    /// Use it to resend subscription or hello messages so a stream resumes
+    /// where it left off. If f fails the error is returned from `connect`
+    /// with the new connection left open.
+    pub fn set_on_reconnect<F: FnMut(&mut SimpleSockleClient) -> Result<()> + Send + 'static>(&mut self, f: F)
+    {
+        self.on_reconnect = Some(Box::new(f));
+    }
+
+    /// Closes the current connection, if any, then connects to url once the
+    /// server has let go of the old one
+    ///
+    /// Unlike `close` followed by `connect`, waits past the close handshake
+    /// until the server drops the TCP connection. A sockle server only does
+    /// that after removing the connection and running `on_disconnect`, so
+    /// servers keying sessions by identity see the disconnect before the
+    /// new connection arrives. Fails with `SocketCloseTimeout` if that
+    /// takes longer than the close timeout, leaving the client disconnected
+    /// so `connect` can go ahead regardless.
+    pub fn reconnect(&mut self, url: &str) -> Result<()>
+    {
+        if self.socket.is_some()
+        {
+            log::info!("Closing socket to reconnect");
+            let deadline = Instant::now() + CLOSE_TIMEOUT;
+            let result = self.close_handshake(Some(CloseFrame { code:   CloseCode::Normal,
+                                                                reason: "Client reconnecting".into() }),
+                                              deadline)
+                             .and_then(|_| self.wait_for_disconnect(deadline));
+            self.socket = None;
+            result?;
+        }
+        self.connect(url)
+    }
+
+    /// Reads the raw stream until the server closes it, discarding anything
+    /// sent after the close handshake
+    fn wait_for_disconnect(&mut self, deadline: Instant) -> Result<(), SimpleSockleError>
+    {
+        use std::io::ErrorKind::{Interrupted, TimedOut, WouldBlock};
+
+        let mut buf = [0; 256];
+        loop
+        {
+            let now = Instant::now();
+            if now >= deadline
+            {
+                log::debug!("Server didn't drop the connection after close");
+                return Err(SimpleSockleError::SocketCloseTimeout);
+            }
+            self.set_timeout(Some(deadline - now))?;
+            match self.socket.as_mut().unwrap().get_mut().read(&mut buf)
+            {
+                Ok(0) => return Ok(()),
+                Ok(_) =>
+                {}
+                Err(e) if matches!(e.kind(), Interrupted | TimedOut | WouldBlock) =>
+                {}
+                // Reset, gone all the same
+                Err(_) => return Ok(())
+            }
+        }
+    }
+
+    /// Runs the reconnect hook if this isn't the first connection
+    pub(crate) fn connected(&mut self) -> Result<()>
+    {
+        let reconnect = self.has_connected;
+        self.has_connected = true;
+        if !reconnect
+        {
+            return Ok(());
+        }
+        if let Some(mut f) = self.on_reconnect.take()
+        {
+            log::debug!("Running reconnect hook");
+            let result = f(self);
+            self.on_reconnect = Some(f);
+            result?;
+        }
+        Ok(())
+    }
+}
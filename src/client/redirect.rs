@@ -0,0 +1,64 @@
+use super::*;
+
+/// Configured headers only sent to the origin connected to, not to another
+/// one a redirect leads to
+pub(crate) const CREDENTIAL_HEADERS: [&str; 3] = ["Authorization", "Cookie", "Proxy-Authorization"];
+
+impl SimpleSockleClient
+{
+    /// Dials url and runs the handshake, following redirects as the config
+    /// allows
+    ///
+    /// Credential headers are dropped once a redirect leaves url's origin,
+    /// a change of scheme, host or port.
+    pub(crate) fn dial_and_handshake(&mut self, url: Url, tls: Option<TlsConfig>) -> Result<(), SimpleSockleError>
+    {
+        let origin = url.origin();
+        let mut visited = vec![url.clone()];
+        let mut url = url;
+        loop
+        {
+            let stream = self.dial(&url)?;
+            let credentials = url.origin() == origin;
+            let location = match self.handshake(url.clone(), Box::new(stream), tls.clone(), credentials)
+            {
+                Err(SimpleSockleError::Redirected { location, .. }) if visited.len() <= self.config.max_redirects =>
+                {
+                    location
+                }
+                r => return r
+            };
+            url = self.redirect_target(&url, &location)?;
+            if visited.contains(&url)
+            {
+                return Err(SimpleSockleError::RedirectRejected(format!("Redirect loop at {url}")));
+            }
+            log::info!("Handshake redirected, connecting to {url}");
+            visited.push(url.clone());
+        }
+    }
+
+    /// Url a redirect from url to location leads to, rejecting downgrades
+    /// to plain text unless allowed
+    fn redirect_target(&self, url: &Url, location: &str) -> Result<Url, SimpleSockleError>
+    {
+        let mut target = url.join(location)
+                            .map_err(|e| SimpleSockleError::RedirectRejected(format!("Invalid location {location}: {e}")))?;
+        let scheme = match target.scheme()
+        {
+            "ws" | "http" => "ws",
+            "wss" | "https" => "wss",
+            s => return Err(SimpleSockleError::RedirectRejected(format!("Unsupported scheme {s}")))
+        };
+        if scheme != target.scheme()
+        {
+            // Only fails for cannot-be-a-base urls, ruled out by the match
+            let _ = target.set_scheme(scheme);
+        }
+        if url.scheme() == "wss" && scheme == "ws" && !self.config.allow_insecure_redirects
+        {
+            return Err(SimpleSockleError::RedirectRejected(format!("Insecure redirect to {target}")));
+        }
+        Ok(target)
+    }
+}
@@ -1,9 +1,43 @@
 use super::*;
-use tungstenite::{stream::MaybeTlsStream, Error};
+use std::{collections::VecDeque,
+          net::{TcpStream, ToSocketAddrs}};
+use tungstenite::{client::IntoClientRequest,
+                  handshake::{client::{ClientHandshake, Request},
+                              HandshakeError},
+                  error::ProtocolError,
+                  http::{HeaderName, HeaderValue, StatusCode},
+                  protocol::WebSocketConfig,
+                  stream::MaybeTlsStream,
+                  Error};
+
+/// How long closing waits for the server to acknowledge
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Stream under the websocket, TCP or a `connect_stream` transport, with
+/// TLS for wss urls
+pub type ClientStream = MaybeTlsStream<Box<dyn SockleTransport>>;
 
 pub struct SimpleSockleClient
 {
-    pub(crate) socket: Option<tungstenite::WebSocket<MaybeTlsStream<std::net::TcpStream>>>
+    pub(crate) socket:        Option<tungstenite::WebSocket<ClientStream>>,
+    pub(crate) config:        SockleClientConfig,
+    pub(crate) heartbeat:     Option<Heartbeat>,
+    /// Encoded frames waiting for `flush`
+    pub(crate) queued:        Vec<u8>,
+    /// Number of frames in `queued`
+    pub(crate) queued_count:  usize,
+    /// Sec-WebSocket-Extensions sent back by the server in the handshake
+    pub(crate) extensions:    Option<String>,
+    pub(crate) on_reconnect:  Option<OnReconnectFn>,
+    /// Messages read ahead by `peek` or `wait_ready`, returned by the next
+    /// reads
+    pub(crate) peeked:        VecDeque<String>,
+    /// Whether any connect has succeeded, later ones are reconnects
+    pub(crate) has_connected: bool,
+    pub(crate) cancel:        ReadCancelToken,
+    /// Payload bytes of writes left in tungstenite's buffer after
+    /// blocking, cleared once a write completes
+    pub(crate) unflushed:     usize
 }
 
 impl Default for SimpleSockleClient
@@ -18,51 +52,262 @@ impl SimpleSockleClient
 {
     pub fn new() -> Self
     {
-        Self { socket: None }
+        Self::with_config(SockleClientConfig::default())
     }
 
-    pub(crate) fn set_non_blocking(&self, value: bool) -> Result<(), SimpleSockleError>
+    /// Client using the given settings each time it connects
+    pub fn with_config(config: SockleClientConfig) -> Self
+    {
+        Self { socket: None,
+               config,
+               heartbeat: None,
+               queued: Vec::new(),
+               queued_count: 0,
+               extensions: None,
+               on_reconnect: None,
+               peeked: VecDeque::new(),
+               has_connected: false,
+               cancel: ReadCancelToken::default(),
+               unflushed: 0 }
+    }
+
+    /// Sets TCP_NODELAY on the connected socket, disabling Nagle's algorithm
+    pub fn set_nodelay(&self, value: bool) -> Result<(), SimpleSockleError>
+    {
+        self.error_if_closed()?;
+        self.transport().set_nodelay(value).map_err(SimpleSockleError::IoError)
+    }
+
+    /// Whether TCP_NODELAY is applied when connecting, defaults to true
+    ///
+    /// Same as setting `SockleClientConfig::nodelay`.
+    pub fn set_nodelay_on_connect(&mut self, value: bool)
+    {
+        self.config.nodelay = value;
+    }
+
+    /// Runs the websocket handshake over a connected stream and stores the socket
+    ///
+    /// tls overrides the TLS settings from the config for this connection.
+    pub(crate) fn handshake(&mut self,
+                            url: Url,
+                            stream: Box<dyn SockleTransport>,
+                            tls: Option<TlsConfig>,
+                            credentials: bool)
+                            -> Result<(), SimpleSockleError>
+    {
+        stream.set_read_timeout(self.config.connect_timeout)
+              .map_err(SimpleSockleError::IoError)?;
+        stream.set_write_timeout(self.config.connect_timeout)
+              .map_err(SimpleSockleError::IoError)?;
+        let connector = tls.or_else(|| self.config.tls.clone())
+                           .map(|tls| tungstenite::Connector::NativeTls(tls.connector()));
+        let request = self.upgrade_request(url, credentials)?;
+        let (socket, response) = tungstenite::client_tls_with_config(request,
+                                                                     stream,
+                                                                     Some(self.config.websocket_config()),
+                                                                     connector)
+                                     .map_err(SimpleSockleClient::map_handshake_error)?;
+        self.set_connected(socket)?;
+        self.extensions = response.headers()
+                                  .get("Sec-WebSocket-Extensions")
+                                  .and_then(|v| v.to_str().ok())
+                                  .map(str::to_string);
+        Ok(())
+    }
+
+    /// Builds the upgrade request for url with the configured headers,
+    /// leaving out those carrying credentials unless credentials is set
+    fn upgrade_request(&self, url: Url, credentials: bool) -> Result<Request, SimpleSockleError>
     {
-        let socket = self.socket.as_ref().unwrap();
-        match socket.get_ref()
+        let mut request = url.into_client_request().map_err(SimpleSockleClient::map_error)?;
+        for (name, value) in &self.config.headers
         {
-            MaybeTlsStream::Plain(s) =>
+            if !credentials && CREDENTIAL_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
             {
-                s.set_nonblocking(value)
-                 .map_err(|x| SimpleSockleError::IoError(x))?
+                log::debug!("Not sending {name} header to {}", request.uri());
+                continue;
             }
-            MaybeTlsStream::NativeTls(s) =>
-            {
-                s.get_ref()
-                 .set_nonblocking(value)
-                 .map_err(|x| SimpleSockleError::IoError(x))?
-            }
-            _ => unimplemented!("RustLs not supported")
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| SimpleSockleError::SocketError(e.into()))?;
+            let value = HeaderValue::from_str(value).map_err(|e| SimpleSockleError::SocketError(e.into()))?;
+            request.headers_mut().append(name, value);
         }
+        Ok(request)
+    }
+
+    /// Same as `connect`, but with a url that has already been parsed
+    pub fn connect_url(&mut self, url: Url) -> Result<()>
+    {
+        log::info!("Connecting socket ({url})");
+
+        if self.error_if_closed().is_ok()
+        {
+            return Err(SimpleSockleError::SocketConnected.into());
+        }
+
+        self.dial_and_handshake(url, None)?;
+        self.connected()?;
+
+        log::info!("Connected");
         Ok(())
     }
 
-    pub(crate) fn set_timeout(&self, value: Option<Duration>) -> Result<(), SimpleSockleError>
+    /// Connects over an already open stream, e.g. the `MemoryStream` from
+    /// `SimpleSockleServer::connect_in_memory`
+    ///
+    /// url only fills in the upgrade request, nothing is dialled and
+    /// redirects aren't followed. For wss urls TLS runs over the stream.
+    /// Reconnecting dials url like any other connection.
+    pub fn connect_stream<T: SockleTransport>(&mut self, url: &str, stream: T) -> Result<()>
     {
-        let socket = self.socket.as_ref().unwrap();
-        match socket.get_ref()
+        log::info!("Connecting socket ({url}) over a given stream");
+
+        if self.error_if_closed().is_ok()
         {
-            MaybeTlsStream::Plain(s) =>
-            {
-                s.set_read_timeout(value)
-                 .map_err(|x| SimpleSockleError::IoError(x))?
-            }
-            MaybeTlsStream::NativeTls(s) =>
-            {
-                s.get_ref()
-                 .set_read_timeout(value)
-                 .map_err(|x| SimpleSockleError::IoError(x))?
-            }
-            _ => unimplemented!("RustLs not supported")
+            return Err(SimpleSockleError::SocketConnected.into());
+        }
+
+        let url = Url::parse(url).map_err(|e| SimpleSockleError::InvalidUrl(e.to_string()))?;
+        SimpleSockleClient::host_and_port(&url)?;
+        self.handshake(url, Box::new(stream), None, true)?;
+        self.connected()?;
+
+        log::info!("Connected");
+        Ok(())
+    }
+
+    /// Connects to the address in url, but presents host to the server
+    ///
+    /// host goes in the Host header and, for wss urls, the TLS SNI and the
+    /// name the certificate is checked against. For reaching name based
+    /// virtual hosts by IP. The url's port is kept in the Host header when
+    /// not the default. Redirects aren't followed.
+    pub fn connect_with_host(&mut self, url: &str, host: &str) -> Result<()>
+    {
+        log::info!("Connecting socket ({url}) as {host}");
+
+        if self.error_if_closed().is_ok()
+        {
+            return Err(SimpleSockleError::SocketConnected.into());
         }
+
+        let url = Url::parse(url).map_err(|e| SimpleSockleError::InvalidUrl(e.to_string()))?;
+        let mut request_url = url.clone();
+        request_url.set_host(Some(host))
+                   .map_err(|e| SimpleSockleError::InvalidUrl(format!("Invalid host {host}: {e}")))?;
+        let stream = self.dial(&url)?;
+        self.handshake(request_url, Box::new(stream), None, true)?;
+        self.connected()?;
+
+        log::info!("Connected");
         Ok(())
     }
 
+    /// The underlying tungstenite socket, None if not connected
+    ///
+    /// An escape hatch for what the client doesn't cover, e.g. sending a
+    /// raw frame. Bypasses the client's bookkeeping: messages queued with
+    /// `queue` or peeked aren't seen, timeouts and blocking mode set here
+    /// may be overwritten and closing it directly leaves the client
+    /// believing it is connected until the next call fails. Prefer the
+    /// client's own methods wherever they suffice.
+    pub fn as_raw_mut(&mut self) -> Option<&mut tungstenite::WebSocket<ClientStream>>
+    {
+        self.socket.as_mut()
+    }
+
+    /// Settings in effect on the current connection, None if not connected
+    pub fn websocket_config(&self) -> Option<WebSocketConfig>
+    {
+        self.socket.as_ref().map(|s| *s.get_config())
+    }
+
+    /// Extensions the server agreed to in the handshake, as sent in its
+    /// Sec-WebSocket-Extensions header
+    ///
+    /// None if not connected or the server didn't send the header.
+    pub fn extensions(&self) -> Option<&str>
+    {
+        self.socket.as_ref().and(self.extensions.as_deref())
+    }
+
+    /// Whether permessage-deflate is active on the current connection
+    ///
+    /// Always false, the websocket library in use can't compress so it is
+    /// never requested.
+    pub fn is_compressed(&self) -> bool
+    {
+        false
+    }
+
+    /// Stores a newly connected socket and applies socket options
+    pub(crate) fn set_connected(&mut self,
+                                socket: tungstenite::WebSocket<ClientStream>)
+                                -> Result<(), SimpleSockleError>
+    {
+        self.socket = Some(socket);
+        self.queued.clear();
+        self.queued_count = 0;
+        self.unflushed = 0;
+        self.peeked.clear();
+        self.extensions = None;
+        self.heartbeat = self.config.heartbeat.map(Heartbeat::new);
+        self.set_timeout(None)?;
+        self.set_write_timeout(None)?;
+        self.set_nodelay(self.config.nodelay)
+    }
+
+    /// Transport underneath the connected socket
+    pub(crate) fn transport(&self) -> &dyn SockleTransport
+    {
+        match self.socket.as_ref().unwrap().get_ref()
+        {
+            MaybeTlsStream::Plain(s) => s.as_ref(),
+            MaybeTlsStream::NativeTls(s) => s.get_ref().as_ref(),
+            _ => unimplemented!("RustLs not supported")
+        }
+    }
+
+    /// Clone of the underlying TCP stream, sharing the same socket
+    ///
+    /// Fails with `StreamCloneUnsupported` on TLS connections: reading or
+    /// writing the raw socket would bypass the TLS session. Also on
+    /// connections from `connect_stream` that aren't over TCP.
+    pub fn try_clone_stream(&self) -> Result<TcpStream, SimpleSockleError>
+    {
+        self.error_if_closed()?;
+        match self.socket.as_ref().unwrap().get_ref()
+        {
+            MaybeTlsStream::Plain(s) => match s.as_tcp()
+            {
+                Some(s) => s.try_clone().map_err(SimpleSockleError::IoError),
+                None => Err(SimpleSockleError::StreamCloneUnsupported)
+            },
+            _ => Err(SimpleSockleError::StreamCloneUnsupported)
+        }
+    }
+
+    pub(crate) fn set_non_blocking(&self, value: bool) -> Result<(), SimpleSockleError>
+    {
+        self.transport()
+            .set_nonblocking(value)
+            .map_err(SimpleSockleError::IoError)
+    }
+
+    pub(crate) fn set_timeout(&self, value: Option<Duration>) -> Result<(), SimpleSockleError>
+    {
+        self.transport()
+            .set_read_timeout(value)
+            .map_err(SimpleSockleError::IoError)
+    }
+
+    pub(crate) fn set_write_timeout(&self, value: Option<Duration>) -> Result<(), SimpleSockleError>
+    {
+        self.transport()
+            .set_write_timeout(value)
+            .map_err(SimpleSockleError::IoError)
+    }
+
     pub(crate) fn read_and_wrap_by_error_kind<F: Fn(std::io::ErrorKind) -> bool>(
         &mut self,
         f: F)
@@ -71,40 +316,106 @@ impl SimpleSockleClient
         match self.read_message()
         {
             Ok(message) => Ok(Some(message)),
-            Err(SimpleSockleError::SocketError(Error::Io(ee))) if f(ee.kind()) => Ok(None),
+            Err(e) if e.io_error_kind().is_some_and(&f) => Ok(None),
             Err(e) => Err(e)
         }
     }
 
+    /// Sends a close frame and reads until the server acknowledges it with
+    /// its own, or drops the connection
+    ///
+    /// Anything else received meanwhile is discarded. Fails with
+    /// `SocketCloseTimeout` if neither happens within `CLOSE_TIMEOUT`, the
+    /// socket is dropped either way.
     pub(crate) fn close_socket(&mut self, cf: Option<CloseFrame>) -> Result<(), SimpleSockleError>
     {
-        use std::time::Instant;
+        let result = self.close_handshake(cf, Instant::now() + CLOSE_TIMEOUT);
+        self.socket = None;
+        result
+    }
+
+    /// Sends a close frame and reads until the server acknowledges it or
+    /// deadline passes, leaving the socket in place
+    pub(crate) fn close_handshake(&mut self, cf: Option<CloseFrame>, deadline: Instant) -> Result<(), SimpleSockleError>
+    {
+        use std::io::ErrorKind::{TimedOut, WouldBlock};
 
-        let socket = self.socket.as_mut().unwrap();
         log::debug!("Sending close frame");
-        if socket.close(cf).is_err()
+        if self.socket.as_mut().unwrap().close(cf).is_err()
         {
             log::debug!("Send close frame failed, assumed already closed");
-            self.socket = None;
             return Ok(());
         }
 
-        log::debug!("Writing pending message until socket closed");
-        let timeout = Instant::now() + Duration::from_secs(2);
-        while !socket.write_pending().is_err() && timeout > Instant::now()
+        log::debug!("Reading until server acknowledges close");
+        loop
         {
-            std::thread::yield_now();
+            let now = Instant::now();
+            if now >= deadline
+            {
+                log::debug!("Socket not closed by server after close frame sent");
+                break Err(SimpleSockleError::SocketCloseTimeout);
+            }
+            if let Err(e) = self.set_non_blocking(false)
+                                .and_then(|_| self.set_timeout(Some(deadline - now)))
+            {
+                log::debug!("Unable to wait for close acknowledgement, assumed closed: {e}");
+                break Ok(());
+            }
+            match self.socket.as_mut().unwrap().read_message()
+            {
+                Ok(Message::Close(_)) => break Ok(()),
+                Ok(_) =>
+                {}
+                Err(Error::Io(e)) if matches!(e.kind(), WouldBlock | TimedOut) =>
+                {}
+                // Connection closed or reset, nothing left to acknowledge
+                Err(_) => break Ok(())
+            }
         }
+    }
 
-        self.socket = None;
-        if timeout < Instant::now()
+    /// Longest message `write` and `queue` accept, see
+    /// `SockleClientConfig::max_write_size`
+    pub fn max_writable_len(&self) -> Option<usize>
+    {
+        self.config.max_write_size
+    }
+
+    /// Fails with `MessageTooLarge` if a message of len bytes can't be sent
+    pub(crate) fn check_writable(&self, len: usize) -> Result<(), SimpleSockleError>
+    {
+        match self.max_writable_len()
         {
-            log::debug!("Socket not closed by server after close frame sent");
-            Err(SimpleSockleError::SocketCloseTimeout)
+            Some(max) if len > max => Err(SimpleSockleError::MessageTooLarge { size: len, max }),
+            _ => Ok(())
         }
-        else
+    }
+
+    /// Approximate number of bytes written or queued but not yet handed to
+    /// the OS
+    ///
+    /// Counts messages waiting for `flush` exactly, plus the payloads of
+    /// writes that timed out or would have blocked and are still buffered.
+    /// Frame headers and control frames aren't included. Drops back to the
+    /// queued amount once a write or flush completes. Useful for throttling
+    /// a producer that writes faster than the connection drains.
+    pub fn pending_write_bytes(&self) -> usize
+    {
+        self.queued.len() + self.unflushed
+    }
+
+    /// Updates `unflushed` after writing len bytes
+    pub(crate) fn track_write(&mut self, len: usize, result: &tungstenite::Result<()>)
+    {
+        use std::io::ErrorKind::{TimedOut, WouldBlock};
+
+        match result
         {
-            Ok(())
+            Ok(()) => self.unflushed = 0,
+            Err(Error::Io(e)) if matches!(e.kind(), WouldBlock | TimedOut) => self.unflushed += len,
+            Err(_) =>
+            {}
         }
     }
 
@@ -122,39 +433,92 @@ impl SimpleSockleClient
 
     pub(crate) fn read_message(&mut self) -> Result<String, SimpleSockleError>
     {
-        let socket = self.socket.as_mut().unwrap();
+        self.read_message_with(SimpleSockleClient::read_any_frame)
+    }
+
+    /// Reads frames with read_frame until a text message arrives
+    pub(crate) fn read_message_with(&mut self,
+                                    read_frame: fn(&mut Self) -> Result<SockleFrame, SimpleSockleError>)
+                                    -> Result<String, SimpleSockleError>
+    {
         loop
         {
-            match socket.read_message()
-                        .map_err(SimpleSockleClient::map_error)?
+            match read_frame(self)?
             {
-                Message::Text(t) => return Ok(t),
-                Message::Binary(_) =>
+                SockleFrame::Text(t) => return Ok(t),
+                SockleFrame::Binary(_) =>
                 {
                     log::error!("Binary data not supported")
                 }
-                Message::Ping(_) =>
+                SockleFrame::Ping(_) =>
                 {
                     log::debug!("Received ping.");
                 }
-                Message::Pong(_) =>
+                SockleFrame::Pong(_) =>
                 {
                     log::debug!("Received pong.");
                 }
-                Message::Close(c) =>
+            }
+        }
+    }
+
+    /// Reads the next frame of any kind, closing the socket on a close frame
+    ///
+    /// Pongs to received pings are flushed straight away.
+    pub(crate) fn read_any_frame(&mut self) -> Result<SockleFrame, SimpleSockleError>
+    {
+        let socket = self.socket.as_mut().unwrap();
+        let message = loop
+        {
+            match socket.read_message()
+            {
+                Err(Error::Utf8) if self.config.skip_invalid_utf8 =>
+                {
+                    log::warn!("Skipping text message that isn't valid UTF-8")
+                }
+                r => break r.map_err(SimpleSockleClient::map_error)?
+            }
+        };
+        if let Some(h) = self.heartbeat.as_mut()
+        {
+            h.seen();
+        }
+        match message
+        {
+            Message::Text(t) => Ok(SockleFrame::Text(t)),
+            Message::Binary(b) => Ok(SockleFrame::Binary(b)),
+            Message::Ping(p) =>
+            {
+                // Flush the queued pong now, a read-only caller may not
+                // read or write again for a while
+                match socket.write_pending()
                 {
-                    log::info!("Received close frame.");
-                    if let Some(c) = c.as_ref()
-                    {
-                        log::info!(" Close reason: {} / {}", c.code, c.reason);
-                    }
-                    let _ = self.close_socket(c);
-                    return Err(SimpleSockleError::SocketDisconnected);
+                    Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock =>
+                    {}
+                    r => r.map_err(SimpleSockleClient::map_error)?
                 }
-                Message::Frame(_) =>
+                Ok(SockleFrame::Ping(p))
+            }
+            Message::Pong(p) => Ok(SockleFrame::Pong(p)),
+            Message::Close(c) =>
+            {
+                log::info!("Received close frame.");
+                if let Some(c) = c.as_ref()
                 {
-                    unreachable!()
+                    log::info!(" Close reason: {} / {}", c.code, c.reason);
                 }
+                let err = match c.as_ref()
+                {
+                    Some(c) => SimpleSockleError::SocketClosed { code:   c.code,
+                                                                 reason: c.reason.to_string() },
+                    None => SimpleSockleError::SocketDisconnected
+                };
+                let _ = self.close_socket(c);
+                Err(err)
+            }
+            Message::Frame(_) =>
+            {
+                unreachable!()
             }
         }
     }
@@ -164,7 +528,110 @@ impl SimpleSockleClient
         match err
         {
             Error::ConnectionClosed | Error::AlreadyClosed => SimpleSockleError::SocketDisconnected,
+            Error::Io(e)
+                if matches!(e.kind(),
+                            std::io::ErrorKind::ConnectionRefused
+                            | std::io::ErrorKind::ConnectionReset
+                            | std::io::ErrorKind::HostUnreachable) =>
+            {
+                SimpleSockleClient::map_io_error(e)
+            }
+            Error::Protocol(ProtocolError::SendAfterClosing) => SimpleSockleError::WriteClosed,
+            Error::Protocol(e) => SimpleSockleError::ProtocolViolation(e.to_string()),
+            Error::SendQueueFull(_) => SimpleSockleError::SendBufferFull,
+            Error::Http(response) if response.status().is_redirection() && response.headers().contains_key("Location") =>
+            {
+                let location = String::from_utf8_lossy(response.headers()["Location"].as_bytes()).to_string();
+                SimpleSockleError::Redirected { status: response.status().as_u16(),
+                                                location }
+            }
+            Error::Http(response) if response.status() == StatusCode::SERVICE_UNAVAILABLE =>
+            {
+                let retry_after = response.headers()
+                                          .get("Retry-After")
+                                          .and_then(|v| v.to_str().ok())
+                                          .and_then(|v| v.trim().parse().ok())
+                                          .map(Duration::from_secs);
+                SimpleSockleError::ServerBusy { retry_after }
+            }
+            Error::Http(response) => SimpleSockleError::HttpRejected { status: response.status().as_u16(),
+                                                                       body:   response.into_body() },
             e => SimpleSockleError::SocketError(e)
         }
     }
+
+    pub(crate) fn map_io_error(err: std::io::Error) -> SimpleSockleError
+    {
+        match err.kind()
+        {
+            std::io::ErrorKind::ConnectionRefused => SimpleSockleError::ConnectionRefused,
+            std::io::ErrorKind::ConnectionReset => SimpleSockleError::ConnectionReset,
+            std::io::ErrorKind::HostUnreachable => SimpleSockleError::HostUnreachable,
+            _ => SimpleSockleError::IoError(err)
+        }
+    }
+
+    /// Opens the TCP connection for url
+    pub(crate) fn dial(&self, url: &Url) -> Result<TcpStream, SimpleSockleError>
+    {
+        let (host, port) = SimpleSockleClient::host_and_port(url)?;
+        SimpleSockleClient::connect_tcp((host.as_str(), port), self.config.connect_timeout)
+    }
+
+    /// Connects to the first address that accepts, giving up on each after timeout
+    pub(crate) fn connect_tcp<A: ToSocketAddrs>(address: A,
+                                                timeout: Option<Duration>)
+                                                -> Result<TcpStream, SimpleSockleError>
+    {
+        let timeout = match timeout
+        {
+            Some(t) => t,
+            None => return TcpStream::connect(address).map_err(SimpleSockleClient::map_io_error)
+        };
+        let mut last_error = None;
+        for addr in address.to_socket_addrs().map_err(SimpleSockleError::IoError)?
+        {
+            match TcpStream::connect_timeout(&addr, timeout)
+            {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e)
+            }
+        }
+        Err(SimpleSockleClient::map_io_error(last_error.unwrap_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Address resolved to nothing")
+        })))
+    }
+
+    pub(crate) fn map_handshake_error(err: HandshakeError<ClientHandshake<ClientStream>>)
+                                      -> SimpleSockleError
+    {
+        match err
+        {
+            HandshakeError::Failure(e) => SimpleSockleClient::map_error(e),
+            // Only happens when the handshake read or write hits connect_timeout
+            HandshakeError::Interrupted(_) =>
+            {
+                SimpleSockleError::IoError(std::io::Error::new(std::io::ErrorKind::TimedOut, "Handshake timed out"))
+            }
+        }
+    }
+
+    /// Address to dial for url, failing for anything but ws and wss urls
+    pub(crate) fn host_and_port(url: &Url) -> Result<(String, u16), SimpleSockleError>
+    {
+        if !matches!(url.scheme(), "ws" | "wss")
+        {
+            return Err(SimpleSockleError::InvalidUrl(format!("Expected ws or wss scheme, got {}", url.scheme())));
+        }
+        // host_str keeps the brackets around IPv6 addresses, which don't resolve
+        let host = match url.host()
+        {
+            Some(url::Host::Ipv6(ip)) => ip.to_string(),
+            Some(host) => host.to_string(),
+            None => return Err(SimpleSockleError::InvalidUrl("Missing host".to_string()))
+        };
+        let port = url.port_or_known_default()
+                      .ok_or_else(|| SimpleSockleError::InvalidUrl("Missing port".to_string()))?;
+        Ok((host, port))
+    }
 }
@@ -0,0 +1,246 @@
+use super::*;
+use std::{collections::VecDeque,
+          io::{Read, Write},
+          net::{SocketAddr, TcpStream},
+          sync::{Arc, Mutex}};
+use tungstenite::{protocol::Role, stream::MaybeTlsStream, WebSocket};
+
+/// One half of a split client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Half
+{
+    Reader,
+    Writer
+}
+
+/// Write side of the socket, shared by both halves of a split client
+struct SharedWrite
+{
+    stream:    TcpStream,
+    /// Half that sent the client's close frame
+    closed_by: Option<Half>
+}
+
+impl SharedWrite
+{
+    /// Records half as the one sending the close frame, false if the other
+    /// half already has
+    fn claim_close(&mut self, half: Half) -> bool
+    {
+        *self.closed_by.get_or_insert(half) == half
+    }
+}
+
+/// Stream used by both halves of a split client
+///
+/// Reads use the half's own clone of the socket, writes go through a shared
+/// lock so frames from the writer and the reader's automatic pongs don't
+/// interleave. Once one half has sent a close frame writes from the other
+/// are dropped, so the client never sends a second one.
+pub struct SplitStream
+{
+    read:  TcpStream,
+    write: Arc<Mutex<SharedWrite>>,
+    half:  Half
+}
+
+impl Read for SplitStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        self.read.read(buf)
+    }
+}
+
+impl Write for SplitStream
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        let mut write = self.write.lock().unwrap();
+        if write.closed_by.is_some_and(|h| h != self.half)
+        {
+            return Ok(buf.len());
+        }
+        write.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        self.write.lock().unwrap().stream.flush()
+    }
+}
+
+impl SockleTransport for SplitStream
+{
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>
+    {
+        self.read.set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        self.read.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        self.read.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        self.read.peer_addr()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        self.read.local_addr()
+    }
+}
+
+/// Reading half of a split client, see `SimpleSockleClient::split`
+///
+/// Answers the server's close frame unless the writer has already closed.
+pub struct SockleReader
+{
+    socket: WebSocket<ClientStream>,
+    peeked: VecDeque<String>,
+    write:  Arc<Mutex<SharedWrite>>
+}
+
+/// Writing half of a split client, see `SimpleSockleClient::split`
+pub struct SockleWriter
+{
+    socket: WebSocket<SplitStream>,
+    write:  Arc<Mutex<SharedWrite>>
+}
+
+impl SimpleSockleClient
+{
+    /// Splits the client so reads and writes can happen on separate threads
+    ///
+    /// Only supported on plain (ws) connections, TLS streams can't be cloned
+    /// and fail with `StreamCloneUnsupported`.
+    /// Messages queued with `queue` are sent first. Messages already peeked
+    /// or read ahead are returned by the reader before anything newer.
+    pub fn split(mut self) -> Result<(SockleWriter, SockleReader)>
+    {
+        let write = Arc::new(Mutex::new(SharedWrite { stream:    self.try_clone_stream()?,
+                                                      closed_by: None }));
+        let read = self.try_clone_stream()?;
+        let writer_read = self.try_clone_stream()?;
+        self.flush_queued()?;
+        let mut socket = self.socket.take().unwrap();
+        socket.write_pending()
+              .map_err(SimpleSockleClient::map_error)?;
+        // The reader keeps the client's socket, and with it anything read
+        // ahead of the peeked messages
+        *socket.get_mut() = MaybeTlsStream::Plain(Box::new(SplitStream { read,
+                                                                         write: write.clone(),
+                                                                         half: Half::Reader }));
+        let writer = WebSocket::from_raw_socket(SplitStream { read:  writer_read,
+                                                              write: write.clone(),
+                                                              half:  Half::Writer },
+                                                Role::Client,
+                                                Some(*socket.get_config()));
+        Ok((SockleWriter { socket: writer,
+                           write:  write.clone() },
+            SockleReader { socket,
+                           peeked: std::mem::take(&mut self.peeked),
+                           write }))
+    }
+}
+
+impl SockleWriter
+{
+    /// Writes a string message to the socket
+    pub fn write(&mut self, msg: String) -> Result<()>
+    {
+        self.error_if_closed()?;
+        Ok(self.socket
+               .write_message(Message::Text(msg))
+               .map_err(SimpleSockleClient::map_error)?)
+    }
+
+    /// Sends a ping
+    pub fn ping(&mut self) -> Result<()>
+    {
+        self.error_if_closed()?;
+        Ok(self.socket
+               .write_message(Message::Ping(vec![0]))
+               .map_err(SimpleSockleClient::map_error)?)
+    }
+
+    /// Sends a close frame, the reader returns an error once the server
+    /// acknowledges it
+    ///
+    /// Does nothing if the server closed first, the reader has answered it.
+    pub fn close(&mut self) -> Result<()>
+    {
+        if !self.write.lock().unwrap().claim_close(Half::Writer)
+        {
+            return Ok(());
+        }
+        self.socket
+            .close(Some(CloseFrame { code:   tungstenite::protocol::frame::coding::CloseCode::Normal,
+                                     reason: "Client requested close".into() }))
+            .map_err(SimpleSockleClient::map_error)?;
+        Ok(())
+    }
+
+    /// SocketDisconnected once the reader has answered the server's close
+    fn error_if_closed(&self) -> Result<(), SimpleSockleError>
+    {
+        match self.write.lock().unwrap().closed_by
+        {
+            Some(Half::Reader) => Err(SimpleSockleError::SocketDisconnected),
+            _ => Ok(())
+        }
+    }
+}
+
+impl SockleReader
+{
+    /// Reads and blocks until a message is returned
+    pub fn read(&mut self) -> Result<String>
+    {
+        if let Some(m) = self.peeked.pop_front()
+        {
+            return Ok(m);
+        }
+        loop
+        {
+            match self.socket
+                      .read_message()
+                      .map_err(SimpleSockleClient::map_error)?
+            {
+                Message::Text(t) => return Ok(t),
+                Message::Binary(_) =>
+                {
+                    log::error!("Binary data not supported")
+                }
+                Message::Ping(_) | Message::Pong(_) =>
+                {}
+                Message::Close(c) =>
+                {
+                    log::info!("Received close frame.");
+                    let answer = self.write.lock().unwrap().claim_close(Half::Reader);
+                    if answer
+                    {
+                        let _ = self.socket.write_pending();
+                    }
+                    return Err(match c
+                               {
+                                   Some(c) => SimpleSockleError::SocketClosed { code:   c.code,
+                                                                                reason: c.reason.to_string() },
+                                   None => SimpleSockleError::SocketDisconnected
+                               }.into());
+                }
+                Message::Frame(_) =>
+                {
+                    unreachable!()
+                }
+            }
+        }
+    }
+}
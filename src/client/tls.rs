@@ -0,0 +1,113 @@
+use super::*;
+use native_tls::{Certificate, TlsConnector};
+use tungstenite::stream::MaybeTlsStream;
+
+/// TLS settings used by `connect_tls` or `SockleClientConfig::tls`
+///
+/// Without one, certificates are verified against the system roots,
+/// relaxing verification has to be asked for explicitly here.
+#[derive(Clone)]
+pub struct TlsConfig
+{
+    connector: TlsConnector
+}
+
+impl TlsConfig
+{
+    /// Uses a fully customised connector
+    pub fn from_connector(connector: TlsConnector) -> Self
+    {
+        Self { connector }
+    }
+
+    /// Verifies certificates against the OS trust store only
+    ///
+    /// Same roots native-tls uses by default, built explicitly so the
+    /// behaviour doesn't depend on the handshake's defaults.
+    pub fn native() -> Result<Self>
+    {
+        Ok(Self { connector: TlsConnector::new()? })
+    }
+
+    /// Trusts the given PEM encoded CA certificate in addition to the system roots
+    ///
+    /// Useful for pinning a private CA or a self-signed server certificate.
+    pub fn with_root_certificate_pem(pem: &[u8]) -> Result<Self>
+    {
+        let cert = Certificate::from_pem(pem)?;
+        let connector = TlsConnector::builder().add_root_certificate(cert).build()?;
+        Ok(Self { connector })
+    }
+
+    /// Accepts any certificate, including self-signed and expired ones
+    ///
+    /// **Never use in production**, this disables all protection against
+    /// man-in-the-middle attacks.
+    pub fn danger_accept_invalid_certs() -> Result<Self>
+    {
+        log::warn!("TLS certificate verification disabled");
+        let connector = TlsConnector::builder().danger_accept_invalid_certs(true)
+                                               .build()?;
+        Ok(Self { connector })
+    }
+
+    pub(crate) fn connector(&self) -> TlsConnector
+    {
+        self.connector.clone()
+    }
+}
+
+impl SimpleSockleClient
+{
+    /// Connects socket to url using the given TLS settings
+    ///
+    /// Only affects wss urls, ws urls connect in plain text as usual.
+    pub fn connect_tls(&mut self, url: &str, tls: TlsConfig) -> Result<()>
+    {
+        log::info!("Connecting socket ({url}) with custom TLS config");
+
+        if self.error_if_closed().is_ok()
+        {
+            return Err(SimpleSockleError::SocketConnected.into());
+        }
+
+        let url = Url::parse(url).map_err(|e| SimpleSockleError::InvalidUrl(e.to_string()))?;
+        self.dial_and_handshake(url, Some(tls))?;
+        self.connected()?;
+
+        log::info!("Connected");
+        Ok(())
+    }
+
+    /// Connects socket to url, verifying certificates against the OS trust
+    /// store
+    ///
+    /// Same as `connect_tls` with `TlsConfig::native`.
+    pub fn connect_tls_native(&mut self, url: &str) -> Result<()>
+    {
+        self.connect_tls(url, TlsConfig::native()?)
+    }
+
+    /// DER encoding of the certificate the server presented, None if not
+    /// connected or not over TLS
+    ///
+    /// For pinning checks or logging the fingerprint of the certificate
+    /// actually trusted.
+    pub fn peer_certificate(&self) -> Option<Vec<u8>>
+    {
+        let stream = match self.socket.as_ref()?.get_ref()
+        {
+            MaybeTlsStream::NativeTls(s) => s,
+            _ => return None
+        };
+        match stream.peer_certificate().and_then(|c| c.map(|c| c.to_der()).transpose())
+        {
+            Ok(der) => der,
+            Err(e) =>
+            {
+                log::warn!("Failed to read peer certificate: {e}");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::borrow::Cow;
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+/// Code and reason sent in a close frame
+///
+/// Shorthand for tungstenite's `CloseFrame`, accepted by
+/// `SockleClient::close_as` and convertible into `Reply::Close`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SockleClose
+{
+    pub code:   CloseCode,
+    pub reason: String
+}
+
+impl SockleClose
+{
+    pub fn new(code: CloseCode, reason: impl Into<String>) -> Self
+    {
+        Self { code,
+               reason: reason.into() }
+    }
+
+    /// Closing because the exchange is done
+    pub fn normal(reason: impl Into<String>) -> Self
+    {
+        Self::new(CloseCode::Normal, reason)
+    }
+
+    /// Closing because the endpoint is going away, e.g. shutting down
+    pub fn away(reason: impl Into<String>) -> Self
+    {
+        Self::new(CloseCode::Away, reason)
+    }
+
+    /// Closing because the peer broke a rule of the application
+    pub fn policy(reason: impl Into<String>) -> Self
+    {
+        Self::new(CloseCode::Policy, reason)
+    }
+
+    /// Closing because of an unexpected error
+    pub fn error(reason: impl Into<String>) -> Self
+    {
+        Self::new(CloseCode::Error, reason)
+    }
+}
+
+impl From<SockleClose> for CloseFrame<'static>
+{
+    fn from(close: SockleClose) -> Self
+    {
+        CloseFrame { code:   close.code,
+                     reason: Cow::Owned(close.reason) }
+    }
+}
@@ -1,3 +1,8 @@
+use tungstenite::{protocol::frame::coding::CloseCode, Error};
+
+/// Error from a user supplied encoder or decoder
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
 #[derive(thiserror::Error, Debug)]
 pub enum SimpleSockleError
 {
@@ -5,12 +10,173 @@ pub enum SimpleSockleError
     InvalidUrl(String),
     #[error("Attempted operation on closed socket")]
     SocketDisconnected,
+    #[error("Socket closed by peer: {code} / {reason}")]
+    SocketClosed
+    {
+        code:   CloseCode,
+        reason: String
+    },
     #[error("Attempted connect on open socket")]
     SocketConnected,
     #[error("Error on underlying socket: {0}")]
     SocketError(tungstenite::Error),
     #[error("IO Error on underlying socket: {0}")]
     IoError(std::io::Error),
+    #[error("Connection refused")]
+    ConnectionRefused,
+    #[error("Connection reset by peer")]
+    ConnectionReset,
+    #[error("Host unreachable")]
+    HostUnreachable,
     #[error("Timeout while trying to close socket")]
-    SocketCloseTimeout
+    SocketCloseTimeout,
+    /// Server answered the handshake with something other than 101
+    ///
+    /// The body is only present if the underlying handshake read it, which
+    /// the current tungstenite version doesn't do.
+    #[error("Handshake rejected by server with status {status}")]
+    HttpRejected
+    {
+        status: u16,
+        body:   Option<String>
+    },
+    /// Server answered the handshake with 503, it is at capacity
+    ///
+    /// retry_after is the delay the server asked for in its Retry-After
+    /// header, None if it didn't send one in seconds.
+    #[error("Server busy, retry after {retry_after:?}")]
+    ServerBusy
+    {
+        retry_after: Option<std::time::Duration>
+    },
+    /// Server answered the handshake with a redirect that wasn't followed,
+    /// see `SockleClientConfig::max_redirects`
+    #[error("Handshake redirected with status {status} to {location}")]
+    Redirected
+    {
+        status:   u16,
+        location: String
+    },
+    /// Redirect refused because it loops, isn't a valid url or would
+    /// downgrade wss to ws
+    #[error("Redirect rejected: {0}")]
+    RedirectRejected(String),
+    #[error("Error establishing proxy tunnel: {0}")]
+    ProxyError(String),
+    /// Peer broke the websocket protocol, e.g. a masked frame from a server
+    /// or an unknown opcode
+    #[error("WebSocket protocol violated by peer: {0}")]
+    ProtocolViolation(String),
+    /// Too many messages waiting to be sent, see
+    /// `SockleClientConfig::max_send_queue`
+    ///
+    /// Nothing was sent, the connection stays usable once the backlog drains.
+    #[error("Send buffer full")]
+    SendBufferFull,
+    /// Outgoing message longer than `SockleClientConfig::max_write_size`,
+    /// nothing was sent
+    #[error("Message of {size} bytes exceeds the {max} byte limit")]
+    MessageTooLarge
+    {
+        size: usize,
+        max:  usize
+    },
+    /// Operation needs a clone of the TCP stream, which TLS connections
+    /// can't provide
+    #[error("Stream can't be cloned, only plain connections support it")]
+    StreamCloneUnsupported,
+    /// User supplied encoder or decoder failed, see
+    /// `SimpleSockleClient::write_encoded`
+    #[error("Unable to encode or decode message: {0}")]
+    Codec(CodecError),
+    /// Blocking read interrupted through a `ReadCancelToken`
+    #[error("Read cancelled")]
+    Cancelled,
+    /// Write attempted after `SimpleSockleClient::close_write`
+    #[error("Attempted write after sending close")]
+    WriteClosed
+}
+
+impl SimpleSockleError
+{
+    /// Kind of the underlying IO error, None if the error didn't come from IO
+    ///
+    /// Covers `IoError`, IO errors wrapped in `SocketError` and the
+    /// `ConnectionRefused`, `ConnectionReset` and `HostUnreachable` variants
+    /// mapped from them.
+    pub fn io_error_kind(&self) -> Option<std::io::ErrorKind>
+    {
+        match self
+        {
+            SimpleSockleError::IoError(e) | SimpleSockleError::SocketError(Error::Io(e)) => Some(e.kind()),
+            SimpleSockleError::ConnectionRefused => Some(std::io::ErrorKind::ConnectionRefused),
+            SimpleSockleError::ConnectionReset => Some(std::io::ErrorKind::ConnectionReset),
+            SimpleSockleError::HostUnreachable => Some(std::io::ErrorKind::HostUnreachable),
+            _ => None
+        }
+    }
+
+    /// Whether reconnecting is worth trying after this error
+    ///
+    /// | Error | Recoverable |
+    /// |-------|-------------|
+    /// | `SocketClosed` with `Away`, `Error`, `Restart`, `Again`, `Abnormal` | yes |
+    /// | `SocketClosed` with any other code (e.g. `Normal`, `Policy`) | no |
+    /// | `SocketDisconnected`, `SocketCloseTimeout` | yes |
+    /// | `ConnectionRefused`, `ConnectionReset`, `HostUnreachable` | yes |
+    /// | IO errors of kind `ConnectionRefused`, `ConnectionReset`, `ConnectionAborted`, `NotConnected`, `BrokenPipe`, `TimedOut`, `WouldBlock`, `Interrupted`, `UnexpectedEof` | yes |
+    /// | Any other IO error | no |
+    /// | `ServerBusy` | yes |
+    /// | HTTP handshake rejected with a 5xx status | yes |
+    /// | HTTP handshake rejected with any other status | no |
+    /// | `Redirected`, `RedirectRejected` | no |
+    /// | `ProtocolViolation`, `SendBufferFull`, `MessageTooLarge`, `Codec`, `Cancelled`, `WriteClosed` | no |
+    /// | TLS, capacity, url and proxy errors | no |
+    /// | `InvalidUrl`, `SocketConnected`, `StreamCloneUnsupported` | no |
+    pub fn is_recoverable(&self) -> bool
+    {
+        match self
+        {
+            SimpleSockleError::SocketClosed { code, .. } => matches!(code,
+                                                                      CloseCode::Away
+                                                                      | CloseCode::Error
+                                                                      | CloseCode::Restart
+                                                                      | CloseCode::Again
+                                                                      | CloseCode::Abnormal),
+            SimpleSockleError::SocketDisconnected
+            | SimpleSockleError::SocketCloseTimeout
+            | SimpleSockleError::ConnectionRefused
+            | SimpleSockleError::ConnectionReset
+            | SimpleSockleError::HostUnreachable
+            | SimpleSockleError::ServerBusy { .. } => true,
+            SimpleSockleError::IoError(e) | SimpleSockleError::SocketError(Error::Io(e)) =>
+            {
+                use std::io::ErrorKind::*;
+                matches!(e.kind(),
+                         ConnectionRefused
+                         | ConnectionReset
+                         | ConnectionAborted
+                         | NotConnected
+                         | BrokenPipe
+                         | TimedOut
+                         | WouldBlock
+                         | Interrupted
+                         | UnexpectedEof)
+            }
+            SimpleSockleError::HttpRejected { status, .. } => (500..600).contains(status),
+            SimpleSockleError::SocketError(_)
+            | SimpleSockleError::InvalidUrl(_)
+            | SimpleSockleError::SocketConnected
+            | SimpleSockleError::StreamCloneUnsupported
+            | SimpleSockleError::Redirected { .. }
+            | SimpleSockleError::RedirectRejected(_)
+            | SimpleSockleError::ProxyError(_)
+            | SimpleSockleError::ProtocolViolation(_)
+            | SimpleSockleError::SendBufferFull
+            | SimpleSockleError::MessageTooLarge { .. }
+            | SimpleSockleError::Codec(_)
+            | SimpleSockleError::Cancelled
+            | SimpleSockleError::WriteClosed => false
+        }
+    }
 }
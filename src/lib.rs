@@ -1,18 +1,40 @@
+#![allow(clippy::result_large_err)]
+
 mod client;
 pub use client::*;
+pub use url::Url;
 
 mod server;
-pub use server::{SimpleSockleServer, SockleServer};
+pub use server::{ConnectionHandle,
+                 ConnectionInfo,
+                 DisconnectReason,
+                 Priority,
+                 Reply,
+                 ServerEvent,
+                 SimpleSockleServer,
+                 SockleHandler,
+                 SockleServer,
+                 SockleServerConfig,
+                 SockleServerHandle,
+                 SockleServerStats,
+                 UptimeStats};
+
+mod close;
+pub use close::SockleClose;
+pub use tungstenite::protocol::frame::coding::CloseCode;
+
+mod transport;
+pub use transport::{MemoryStream, SockleTransport};
 
 mod error;
-pub use error::SimpleSockleError;
+pub use error::{CodecError, SimpleSockleError};
 
 #[cfg(test)]
 mod tests
 {
     use super::*;
     use std::{sync::atomic::{AtomicUsize, Ordering},
-              time::Duration};
+              time::{Duration, Instant}};
 
     fn listen_addr() -> (String, String)
     {
@@ -23,10 +45,7 @@ mod tests
 
     fn wait_for_connections(server: &SimpleSockleServer, count: usize)
     {
-        while server.connection_count() < count
-        {
-            std::thread::yield_now()
-        }
+        assert!(server.wait_for_connections(count, Duration::from_secs(5)));
     }
 
     #[test]
@@ -35,10 +54,9 @@ mod tests
         let _ = pretty_env_logger::try_init();
         let mut s = SimpleSockleClient::new();
         let mut server = SimpleSockleServer::new();
-        let addr = listen_addr();
-        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
 
-        s.connect(&addr.1).expect("Connect");
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).expect("Connect");
 
         wait_for_connections(&server, 1);
 
@@ -50,15 +68,218 @@ mod tests
     }
 
     #[test]
-    fn when_no_data_try_read_should_return_none()
+    fn server_socket_options()
     {
         let _ = pretty_env_logger::try_init();
         let mut s = SimpleSockleClient::new();
-        let mut server = SimpleSockleServer::new();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { nodelay: true,
+                                                                              recv_buffer_size: Some(64 * 1024),
+                                                                              send_buffer_size: Some(64 * 1024),
+                                                                              poll_interval: Duration::from_millis(1),
+                                                                              ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect(&addr.1).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn server_poll_interval_paces_idle_workers()
+    {
+        let _ = pretty_env_logger::try_init();
+        let long = Duration::from_millis(300);
+        for poll_interval in [Duration::from_millis(1), long]
+        {
+            let mut s = SimpleSockleClient::new();
+            let mut server = SimpleSockleServer::with_config(SockleServerConfig { poll_interval,
+                                                                                  ..Default::default() });
+            let addr = listen_addr();
+            server.listen(&addr.0, |m, f| {
+                      f(m);
+                      Ok(())
+                  })
+                  .unwrap();
+
+            s.connect(&addr.1).unwrap();
+            wait_for_connections(&server, 1);
+            assert_eq!(s.request("Warm up".to_string(), Duration::from_secs(5)).unwrap().as_deref(),
+                       Some("Warm up"));
+
+            // Lets the worker go idle so the request waits out its sleep
+            std::thread::sleep(Duration::from_millis(50));
+            let start = Instant::now();
+            assert_eq!(s.request("Test".to_string(), Duration::from_secs(5)).unwrap().as_deref(),
+                       Some("Test"));
+            let elapsed = start.elapsed();
+            if poll_interval == long
+            {
+                assert!(elapsed >= Duration::from_millis(150), "{elapsed:?}");
+            }
+            else
+            {
+                assert!(elapsed < Duration::from_millis(150), "{elapsed:?}");
+            }
+
+            server.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn server_aborts_stalled_handshake()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { worker_threads: Some(1),
+                                                                              handshake_timeout:
+                                                                                  Some(Duration::from_millis(50)),
+                                                                              ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        // Opens the connection but never sends the upgrade request
+        let _stalled = std::net::TcpStream::connect(&addr.0).unwrap();
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&addr.1).unwrap();
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn server_aborts_trickled_handshake()
+    {
+        use std::io::{Read, Write};
+
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { worker_threads: Some(1),
+                                                                              handshake_timeout:
+                                                                                  Some(Duration::from_millis(300)),
+                                                                              ..Default::default() });
         let addr = listen_addr();
         server.listen(&addr.0, |_, _| Ok(())).unwrap();
 
-        s.connect(&addr.1).expect("Connect");
+        // Each byte arrives well within the timeout, the whole request never does
+        let mut slow = std::net::TcpStream::connect(&addr.0).unwrap();
+        slow.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let started = Instant::now();
+        let mut dropped = false;
+        for b in b"GET / HTTP/1.1\r\nHost: localhost\r\n"
+        {
+            std::thread::sleep(Duration::from_millis(50));
+            if slow.write_all(&[*b]).is_err()
+            {
+                dropped = true;
+                break;
+            }
+            match slow.read(&mut [0; 1])
+            {
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+                {}
+                _ =>
+                {
+                    dropped = true;
+                    break;
+                }
+            }
+        }
+        assert!(dropped);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stalled_handshake_does_not_hold_up_worker()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { worker_threads: Some(1),
+                                                                              handshake_timeout: None,
+                                                                              ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let mut a = SimpleSockleClient::new();
+        a.connect(&addr.1).unwrap();
+        // Never finishes the upgrade request and is never timed out
+        let mut stalled = std::net::TcpStream::connect(&addr.0).unwrap();
+        std::io::Write::write_all(&mut stalled, b"GET / HTTP/1.1\r\n").unwrap();
+
+        let mut b = SimpleSockleClient::new();
+        b.connect(&addr.1).unwrap();
+        assert_eq!(a.request("A".to_string(), Duration::from_secs(1)).unwrap().as_deref(),
+                   Some("A"));
+        assert_eq!(b.request("B".to_string(), Duration::from_secs(1)).unwrap().as_deref(),
+                   Some("B"));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn server_sends_keepalive_pings()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server =
+            SimpleSockleServer::with_config(SockleServerConfig { keepalive_interval: Some(Duration::from_millis(50)),
+                                                                 ..Default::default() });
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        assert!(matches!(s.read_frame().unwrap(), SockleFrame::Ping(_)));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn server_closes_idle_connections()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server =
+            SimpleSockleServer::with_config(SockleServerConfig { idle_timeout: Some(Duration::from_millis(50)),
+                                                                 ..Default::default() });
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { .. }));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn when_no_data_try_read_should_return_none()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).expect("Connect");
 
         assert!(s.try_read().unwrap().is_none());
 
@@ -71,26 +292,45 @@ mod tests
         let _ = pretty_env_logger::try_init();
         let mut s = SimpleSockleClient::new();
         let mut server = SimpleSockleServer::new();
-        let addr = listen_addr();
-        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
 
-        s.connect(&addr.1).expect("Connect");
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).expect("Connect");
 
         assert!(s.read_timeout(Duration::from_millis(15)).unwrap().is_none());
 
         server.shutdown().unwrap();
     }
 
+    #[test]
+    fn when_deadline_passed_read_deadline_should_return_none()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).expect("Connect");
+
+        wait_for_connections(&server, 1);
+
+        server.send("Test".to_string());
+
+        assert!(s.read_deadline(Instant::now()).unwrap().is_none());
+        assert_eq!(s.read_deadline(Instant::now() + Duration::from_secs(5)).unwrap(),
+                   Some("Test".to_string()));
+
+        server.shutdown().unwrap();
+    }
+
     #[test]
     fn when_data_read_should_return_data()
     {
         let _ = pretty_env_logger::try_init();
         let mut s = SimpleSockleClient::new();
         let mut server = SimpleSockleServer::new();
-        let addr = listen_addr();
-        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
 
-        s.connect(&addr.1).expect("Connect");
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).expect("Connect");
 
         wait_for_connections(&server, 1);
 
@@ -102,10 +342,233 @@ mod tests
     }
 
     #[test]
-    fn echo_server()
+    fn peek_does_not_consume()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).expect("Connect");
+
+        wait_for_connections(&server, 1);
+
+        assert_eq!(s.peek().unwrap(), None);
+
+        server.send("First".to_string());
+        server.send("Second".to_string());
+
+        while s.peek().unwrap().is_none()
+        {
+            std::thread::yield_now()
+        }
+        assert_eq!(s.peek().unwrap(), Some("First"));
+        assert_eq!(s.read().unwrap(), "First");
+        assert_eq!(s.read().unwrap(), "Second");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connect_tls_native_connects_plain_urls()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect_tls_native(&addr.1).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        server.shutdown().unwrap();
+    }
+
+    const TEST_CERT: &[u8] = include_bytes!("client/test_certs/localhost.crt");
+    const TEST_KEY: &[u8] = include_bytes!("client/test_certs/localhost.key");
+
+    /// Accepts one wss connection with the self-signed test certificate and
+    /// echoes a single message, returns the url to connect to
+    fn tls_echo_server() -> String
+    {
+        let identity = native_tls::Identity::from_pkcs8(TEST_CERT, TEST_KEY).unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // A client refusing the certificate fails the TLS handshake here
+            let Ok(stream) = acceptor.accept(stream)
+            else
+            {
+                return;
+            };
+            let mut socket = tungstenite::accept(stream).unwrap();
+            let msg = socket.read_message().unwrap();
+            socket.write_message(msg).unwrap();
+        });
+        format!("wss://localhost:{port}/")
+    }
+
+    #[test]
+    fn tls_rejects_self_signed_certificate_by_default()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let url = tls_echo_server();
+
+        let err = s.connect_tls(&url, TlsConfig::native().unwrap()).unwrap_err();
+
+        assert!(err.to_string().contains("certificate verify failed"), "{err}");
+        assert!(s.peer_certificate().is_none());
+    }
+
+    #[test]
+    fn tls_accepts_self_signed_certificate_when_verification_disabled()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let url = tls_echo_server();
+
+        s.connect_tls(&url, TlsConfig::danger_accept_invalid_certs().unwrap()).unwrap();
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+        let expected = native_tls::Certificate::from_pem(TEST_CERT).unwrap().to_der().unwrap();
+        assert_eq!(s.peer_certificate(), Some(expected));
+    }
+
+    #[test]
+    fn tls_trusts_pinned_root_certificate()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let url = tls_echo_server();
+
+        s.connect_tls(&url, TlsConfig::with_root_certificate_pem(TEST_CERT).unwrap()).unwrap();
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+    }
+
+    #[test]
+    fn tls_config_rejects_invalid_root_certificate()
+    {
+        assert!(TlsConfig::with_root_certificate_pem(b"not a certificate").is_err());
+    }
+
+    #[test]
+    fn when_nothing_listening_connect_should_be_refused()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let addr = listen_addr();
+
+        let err = s.connect(&addr.1).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+
+        assert!(matches!(err, SimpleSockleError::ConnectionRefused));
+        assert_eq!(err.io_error_kind(), Some(std::io::ErrorKind::ConnectionRefused));
+    }
+
+    #[test]
+    fn io_error_kind_maps_io_errors()
+    {
+        use std::io::{Error, ErrorKind};
+
+        let io = SimpleSockleError::IoError(Error::new(ErrorKind::TimedOut, "timed out"));
+        let socket = SimpleSockleError::SocketError(tungstenite::Error::Io(Error::new(ErrorKind::BrokenPipe, "broken")));
+
+        assert_eq!(io.io_error_kind(), Some(ErrorKind::TimedOut));
+        assert_eq!(socket.io_error_kind(), Some(ErrorKind::BrokenPipe));
+        assert_eq!(SimpleSockleError::ConnectionRefused.io_error_kind(), Some(ErrorKind::ConnectionRefused));
+        assert_eq!(SimpleSockleError::ConnectionReset.io_error_kind(), Some(ErrorKind::ConnectionReset));
+        assert_eq!(SimpleSockleError::HostUnreachable.io_error_kind(), Some(ErrorKind::HostUnreachable));
+
+        assert_eq!(SimpleSockleError::SocketError(tungstenite::Error::Utf8).io_error_kind(), None);
+        assert_eq!(SimpleSockleError::Cancelled.io_error_kind(), None);
+        assert_eq!(SimpleSockleError::InvalidUrl("bad".to_string()).io_error_kind(), None);
+        assert_eq!(SimpleSockleError::SocketClosed { code:   CloseCode::Normal,
+                                                     reason: String::new() }.io_error_kind(),
+                   None);
+    }
+
+    #[test]
+    fn peer_gone_classifies_write_errors()
+    {
+        use std::io::{Error, ErrorKind};
+
+        let broken = || tungstenite::Error::Io(Error::new(ErrorKind::BrokenPipe, "broken"));
+        let reset = || tungstenite::Error::Io(Error::new(ErrorKind::ConnectionReset, "reset"));
+
+        assert!(DisconnectReason::peer_gone(&broken()));
+        assert!(DisconnectReason::peer_gone(&reset()));
+        assert_eq!(DisconnectReason::from_error(&broken()), DisconnectReason::IoError);
+        assert_eq!(DisconnectReason::from_error(&reset()), DisconnectReason::IoError);
+
+        assert_eq!(SimpleSockleClient::map_error(broken()).io_error_kind(), Some(ErrorKind::BrokenPipe));
+        assert!(matches!(SimpleSockleClient::map_error(reset()), SimpleSockleError::ConnectionReset));
+
+        assert!(!DisconnectReason::peer_gone(&tungstenite::Error::Utf8));
+        assert!(!DisconnectReason::peer_gone(&tungstenite::Error::Io(Error::new(ErrorKind::TimedOut, "timed out"))));
+    }
+
+    #[test]
+    fn connect_with_retry_waits_for_server()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let addr = listen_addr();
+
+        let err = s.connect_with_retry(&addr.1, 2, Duration::from_millis(10))
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::ConnectionRefused));
+
+        let listen = addr.0.clone();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let mut server = SimpleSockleServer::new();
+            server.listen(&listen, |_, _| Ok(())).unwrap();
+            server
+        });
+
+        s.connect_with_retry(&addr.1, 10, Duration::from_millis(20)).unwrap();
+
+        server.join().unwrap().shutdown().unwrap();
+    }
+
+    #[test]
+    fn when_handshake_rejected_connect_should_return_status()
     {
+        use std::{io::{Read, Write},
+                  net::TcpListener};
+
         let _ = pretty_env_logger::try_init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let _ = stream.read(&mut [0u8; 1024]).unwrap();
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")
+                  .unwrap();
+        });
+
         let mut s = SimpleSockleClient::new();
+        let err = s.connect(&url).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+
+        assert!(matches!(err, SimpleSockleError::HttpRejected { status: 403, .. }));
+        assert!(!err.is_recoverable());
+    }
+
+    #[test]
+    fn redirects_are_followed_when_enabled()
+    {
+        use std::{io::{Read, Write},
+                  net::TcpListener};
+
+        let _ = pretty_env_logger::try_init();
         let mut server = SimpleSockleServer::new();
         let addr = listen_addr();
         server.listen(&addr.0, |m, f| {
@@ -114,37 +577,2910 @@ mod tests
               })
               .unwrap();
 
-        s.connect(&addr.1).unwrap();
+        // Redirects every request to location
+        let redirector = |location: String| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("ws://{}/", listener.local_addr().unwrap());
+            std::thread::spawn(move || {
+                for stream in listener.incoming()
+                {
+                    let mut stream = stream.unwrap();
+                    let _ = stream.read(&mut [0u8; 1024]).unwrap();
+                    let response = format!("HTTP/1.1 301 Moved Permanently\r\nLocation: {location}\r\n\r\n");
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+            });
+            url
+        };
+        let url = redirector(addr.1.clone());
 
-        wait_for_connections(&server, 1);
+        let mut s = SimpleSockleClient::new();
+        let err = s.connect(&url).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::Redirected { status: 301, ref location } if *location == addr.1));
 
+        let config = SockleClientConfig { max_redirects: 2,
+                                          ..Default::default() };
+        let mut s = SimpleSockleClient::with_config(config.clone());
+        s.connect(&url).unwrap();
         s.write("Test".to_string()).unwrap();
-
         assert_eq!(s.read().unwrap(), "Test");
+        s.close().unwrap();
+
+        let mut s = SimpleSockleClient::with_config(config);
+        let looped = redirector("/".to_string());
+        let err = s.connect(&looped).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::RedirectRejected(_)));
 
         server.shutdown().unwrap();
     }
 
     #[test]
-    fn broadcast()
+    fn cross_origin_redirect_drops_credentials()
     {
+        use std::{io::{Read, Write},
+                  net::TcpListener,
+                  sync::{Arc, Mutex}};
+
         let _ = pretty_env_logger::try_init();
-        let mut s1 = SimpleSockleClient::new();
-        let mut s2 = SimpleSockleClient::new();
         let mut server = SimpleSockleServer::new();
         let addr = listen_addr();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_h = seen.clone();
+        server.on_handshake(move |request, _| {
+                  let names = request.headers().keys().map(|k| k.to_string()).collect::<Vec<_>>();
+                  seen_h.lock().unwrap().push(names);
+              });
         server.listen(&addr.0, |_, _| Ok(())).unwrap();
 
-        s1.connect(&addr.1).unwrap();
-        s2.connect(&addr.1).unwrap();
+        // Another port is another origin
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let location = addr.1.clone();
+        std::thread::spawn(move || {
+            let mut stream = listener.incoming().next().unwrap().unwrap();
+            let _ = stream.read(&mut [0u8; 1024]).unwrap();
+            let response = format!("HTTP/1.1 307 Temporary Redirect\r\nLocation: {location}\r\n\r\n");
+            stream.write_all(response.as_bytes()).unwrap();
+        });
 
-        wait_for_connections(&server, 2);
+        let config = SockleClientConfig { max_redirects: 1,
+                                          headers: vec![("Authorization".to_string(), "Bearer secret".to_string()),
+                                                        ("Cookie".to_string(), "session=secret".to_string()),
+                                                        ("X-Client".to_string(), "test".to_string())],
+                                          ..Default::default() };
+        let mut direct = SimpleSockleClient::with_config(config.clone());
+        direct.connect(&addr.1).unwrap();
+        let mut redirected = SimpleSockleClient::with_config(config);
+        redirected.connect(&url).unwrap();
 
-        server.send("Test".to_string());
+        let seen = seen.lock().unwrap();
+        assert!(seen[0].iter().any(|h| h == "authorization") && seen[0].iter().any(|h| h == "cookie"));
+        assert!(!seen[1].iter().any(|h| h == "authorization" || h == "cookie"));
+        assert!(seen[1].iter().any(|h| h == "x-client"));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn disallowed_origin_is_rejected()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server =
+            SimpleSockleServer::with_config(SockleServerConfig { allowed_origins:
+                                                                     Some(vec!["https://example.com".to_string()]),
+                                                                 ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        let with_origin = |origin: &str| {
+            SimpleSockleClient::with_config(SockleClientConfig { headers: vec![("Origin".to_string(),
+                                                                                origin.to_string())],
+                                                                 ..Default::default() })
+        };
+
+        let err = with_origin("https://evil.example")
+            .connect(&addr.1)
+            .unwrap_err()
+            .downcast::<SimpleSockleError>()
+            .unwrap();
+        assert!(matches!(err, SimpleSockleError::HttpRejected { status: 403, .. }));
+
+        with_origin("https://EXAMPLE.com").connect(&addr.1).unwrap();
+        SimpleSockleClient::new().connect(&addr.1).unwrap();
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn masked_frame_from_server_is_protocol_violation()
+    {
+        use std::{io::Write, net::TcpListener};
+
+        let _ = pretty_env_logger::try_init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            // Text frame "a" with the mask bit set, servers must not mask
+            socket.get_mut()
+                  .write_all(&[0x81, 0x81, 0, 0, 0, 0, b'a'])
+                  .unwrap();
+            socket
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&url).unwrap();
+
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::ProtocolViolation(_)));
+        assert!(!err.is_recoverable());
+
+        drop(server.join().unwrap());
+    }
+
+    #[test]
+    fn set_nodelay_requires_connection()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        assert!(matches!(s.set_nodelay(true), Err(SimpleSockleError::SocketDisconnected)));
+
+        s.set_nodelay_on_connect(false);
+        s.connect(&addr.1).unwrap();
+
+        assert!(s.set_nodelay(true).is_ok());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn write_over_max_write_size_fails()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let mut s = SimpleSockleClient::with_config(SockleClientConfig { max_write_size: Some(4),
+                                                                         ..Default::default() });
+        assert_eq!(s.max_writable_len(), Some(4));
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        let err = s.write("Too long".to_string())
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::MessageTooLarge { size: 8, max: 4 }));
+
+        s.write("Fits".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Fits");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn client_config_limits()
+    {
+        use std::net::TcpListener;
+
+        let _ = pretty_env_logger::try_init();
+
+        // Accepts the TCP connection but never answers the handshake
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", silent.local_addr().unwrap());
+        let mut s = SimpleSockleClient::with_config(SockleClientConfig { connect_timeout: Some(Duration::from_millis(50)),
+                                                                         ..Default::default() });
+        let err = s.connect(&url).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert_eq!(err.io_error_kind(), Some(std::io::ErrorKind::TimedOut));
+
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        let mut s = SimpleSockleClient::with_config(SockleClientConfig { max_message_size: Some(16),
+                                                                         ..Default::default() });
+        assert!(s.websocket_config().is_none());
+        s.connect(&addr.1).unwrap();
+        assert_eq!(s.websocket_config().unwrap().max_message_size, Some(16));
+        assert!(s.extensions().is_none());
+        assert!(!s.is_compressed());
+
+        wait_for_connections(&server, 1);
+
+        server.send("x".repeat(32));
+
+        assert!(s.read().is_err());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn wait_ready_confirms_round_trip()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.on_connect(|_, handle| handle.send("Hello".to_string()).unwrap());
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+        s.wait_ready(Duration::from_secs(1)).unwrap();
+        assert_eq!(s.read().unwrap(), "Hello");
+        server.shutdown().unwrap();
+
+        // Peer that never reads, so never answers the ping
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (done, done_r) = std::sync::mpsc::channel::<()>();
+        let peer = std::thread::spawn(move || {
+            let socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            let _ = done_r.recv();
+            drop(socket);
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&format!("ws://127.0.0.1:{port}")).unwrap();
+        let err = s.wait_ready(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.io_error_kind(), Some(std::io::ErrorKind::TimedOut));
+
+        done.send(()).unwrap();
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn heartbeat_pings_and_tracks_liveness()
+    {
+        use std::net::TcpListener;
+
+        let _ = pretty_env_logger::try_init();
+        let heartbeat = HeartbeatConfig { interval: Duration::from_millis(20),
+                                          timeout:  Duration::from_millis(100) };
+
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        let mut s = SimpleSockleClient::with_config(SockleClientConfig { heartbeat: Some(heartbeat),
+                                                                         ..Default::default() });
+        s.connect(&addr.1).unwrap();
+
+        assert!(matches!(s.read_frame().unwrap(), SockleFrame::Pong(_)));
+        assert!(s.is_alive());
+
+        server.shutdown().unwrap();
+
+        // Completes the handshake but never answers pings
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", silent.local_addr().unwrap());
+        let silent = std::thread::spawn(move || {
+            let socket = tungstenite::accept(silent.accept().unwrap().0).unwrap();
+            std::thread::sleep(Duration::from_millis(300));
+            drop(socket);
+        });
+
+        let mut s = SimpleSockleClient::with_config(SockleClientConfig { heartbeat: Some(heartbeat),
+                                                                         ..Default::default() });
+        s.connect(&url).unwrap();
+        assert!(s.is_alive());
+        assert!(s.read_timeout(Duration::from_millis(150)).unwrap().is_none());
+        assert!(!s.is_alive());
+
+        s.stop_heartbeat();
+        assert!(s.is_alive());
+
+        silent.join().unwrap();
+    }
+
+    #[test]
+    fn close_with_sends_code_and_reason()
+    {
+        use std::net::TcpListener;
+        use tungstenite::{protocol::frame::coding::CloseCode, Message};
+
+        let _ = pretty_env_logger::try_init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            match socket.read_message().unwrap()
+            {
+                Message::Close(Some(cf)) => (cf.code, cf.reason.to_string()),
+                m => panic!("Expected close frame, got {m:?}")
+            }
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&url).unwrap();
+        let _ = s.close_with(CloseCode::Policy, "Not allowed".to_string());
+
+        assert_eq!(server.join().unwrap(), (CloseCode::Policy, "Not allowed".to_string()));
+        assert!(s.close_with(CloseCode::Policy, "Again".to_string()).is_ok());
+    }
+
+    #[test]
+    fn sockle_close_sets_code_and_reason()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen_with_reply("127.0.0.1:0", |m, reply| {
+                  match m.as_str()
+                  {
+                      "Bye" => reply(SockleClose::policy("Done").into()),
+                      _ => reply(Reply::Text(m))
+                  }
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("Bye".to_string()).unwrap();
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Policy, ref reason } if reason == "Done"));
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.close_as(SockleClose::normal("Finished")).unwrap();
+        assert_eq!(SockleClose::error("Oops").code, CloseCode::Error);
+        assert_eq!(SockleClose::away("Restarting").reason, "Restarting");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_batch_collects_messages_within_window()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, reply| {
+                  for i in 0..3
+                  {
+                      reply(format!("{m} {i}"));
+                  }
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        assert!(s.read_batch(Duration::from_millis(20)).unwrap().is_empty());
+
+        s.write("Test".to_string()).unwrap();
+        let start = Instant::now();
+        let batch = s.read_batch(Duration::from_millis(100)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(batch, ["Test 0", "Test 1", "Test 2"]);
+
+        server.shutdown().unwrap();
+        let batch = s.read_batch(Duration::from_secs(5));
+        assert!(batch.is_err());
+    }
+
+    #[test]
+    fn connect_with_host_sets_host_header()
+    {
+        use std::net::TcpListener;
+        use tungstenite::handshake::server::{Request, Response};
+
+        let _ = pretty_env_logger::try_init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut host = None;
+            let _socket = tungstenite::accept_hdr(listener.accept().unwrap().0, |req: &Request, res: Response| {
+                              host = req.headers().get("Host").map(|h| h.to_str().unwrap().to_string());
+                              Ok(res)
+                          }).unwrap();
+            host
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_with_host(&format!("ws://127.0.0.1:{port}/"), "vhost.example")
+         .unwrap();
+        assert_eq!(server.join().unwrap(), Some(format!("vhost.example:{port}")));
+
+        let mut s = SimpleSockleClient::new();
+        let err = s.connect_with_host(&format!("ws://127.0.0.1:{port}/"), "bad host")
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::InvalidUrl(_)));
+    }
+
+    #[test]
+    fn connection_reports_websocket_config_and_extensions()
+    {
+        use std::net::TcpListener;
+        use tungstenite::handshake::server::{Request, Response};
+
+        let _ = pretty_env_logger::try_init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut socket = tungstenite::accept_hdr(listener.accept().unwrap().0, |_: &Request, mut res: Response| {
+                                 res.headers_mut()
+                                    .insert("Sec-WebSocket-Extensions", "x-test; level=1".parse().unwrap());
+                                 Ok(res)
+                             }).unwrap();
+            // Answers the client's close
+            while socket.read_message().is_ok()
+            {}
+        });
+
+        let mut s = SimpleSockleClient::with_config(SockleClientConfig { max_message_size: Some(1 << 10),
+                                                                         max_send_queue: Some(8),
+                                                                         ..Default::default() });
+        assert!(s.websocket_config().is_none());
+        assert!(s.extensions().is_none());
+
+        s.connect(&format!("ws://127.0.0.1:{port}/")).unwrap();
+        let config = s.websocket_config().unwrap();
+        assert_eq!(config.max_message_size, Some(1 << 10));
+        assert_eq!(config.max_send_queue, Some(8));
+        assert_eq!(config.max_frame_size, tungstenite::protocol::WebSocketConfig::default().max_frame_size);
+        assert_eq!(s.extensions(), Some("x-test; level=1"));
+        assert!(!s.is_compressed());
+
+        s.close().unwrap();
+        server.join().unwrap();
+        assert!(s.websocket_config().is_none());
+        assert!(s.extensions().is_none());
+        assert!(!s.is_compressed());
+    }
+
+    #[test]
+    fn wait_for_connections_times_out()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        let start = Instant::now();
+        assert!(!server.wait_for_connections(1, Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&addr.1).unwrap();
+        assert!(server.wait_for_connections(1, Duration::from_secs(5)));
+        assert_eq!(server.connection_count(), 1);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcast_others_skips_sender()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut a = SimpleSockleClient::new();
+        let mut b = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let handle = server.handle();
+        server.listen_deferred("127.0.0.1:0", move |m, conn| {
+                  handle.broadcast_others(conn.id(), m);
+                  Ok(())
+              })
+              .unwrap();
+
+        a.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        b.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+
+        a.write("From a".to_string()).unwrap();
+        assert_eq!(b.read_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some("From a"));
+        assert!(a.read_timeout(Duration::from_millis(100)).unwrap().is_none());
+
+        assert_eq!(server.broadcast_others(u64::MAX, "All".to_string()), 2);
+        assert_eq!(a.read().unwrap(), "All");
+        assert_eq!(b.read().unwrap(), "All");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcast_others_from_listen_handler()
+    {
+        let _ = pretty_env_logger::try_init();
+        assert!(ConnectionHandle::current().is_none());
+        // Handlers run on the worker, or on a thread of their own under a timeout
+        for handler_timeout in [None, Some(Duration::from_secs(5))]
+        {
+            let mut a = SimpleSockleClient::new();
+            let mut b = SimpleSockleClient::new();
+            let mut server = SimpleSockleServer::with_config(SockleServerConfig { handler_timeout,
+                                                                                  ..Default::default() });
+            let handle = server.handle();
+            server.listen("127.0.0.1:0", move |m, _| {
+                      let sender = ConnectionHandle::current().unwrap();
+                      handle.broadcast_others(sender.id(), m);
+                      Ok(())
+                  })
+                  .unwrap();
+
+            a.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+            b.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+            wait_for_connections(&server, 2);
+
+            a.write("From a".to_string()).unwrap();
+            assert_eq!(b.read_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some("From a"));
+            b.write("From b".to_string()).unwrap();
+            assert_eq!(a.read_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some("From b"));
+            assert!(a.read_timeout(Duration::from_millis(100)).unwrap().is_none());
+            assert!(b.read_timeout(Duration::from_millis(100)).unwrap().is_none());
+
+            server.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn connect_rejects_non_websocket_scheme()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        for url in ["http://127.0.0.1:1/", "ftp://127.0.0.1:1/"]
+        {
+            let err = s.connect(url).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+            assert!(matches!(err, SimpleSockleError::InvalidUrl(ref m) if m.contains("Expected ws or wss")));
+        }
+    }
+
+    #[test]
+    fn peer_certificate_is_none_without_tls()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        assert!(s.peer_certificate().is_none());
+        s.connect(&addr.1).unwrap();
+        assert!(s.peer_certificate().is_none());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn coalesce_window_holds_back_sends()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { coalesce_window: Some(Duration::from_millis(100)),
+                                                                              ..Default::default() });
+        server.listen("127.0.0.1:0", |m, reply| {
+                  reply(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        assert_eq!(s.request("Reply".to_string(), Duration::from_secs(5)).unwrap().as_deref(), Some("Reply"));
+
+        let start = Instant::now();
+        for i in 0..3
+        {
+            server.send(format!("Broadcast {i}"));
+        }
+        assert_eq!(s.read().unwrap(), "Broadcast 0");
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(s.read().unwrap(), "Broadcast 1");
+        assert_eq!(s.read().unwrap(), "Broadcast 2");
+
+        server.shutdown().unwrap();
+        assert!(s.read().is_err());
+    }
+
+    #[test]
+    fn send_to_room_reaches_members_only()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut a = SimpleSockleClient::new();
+        let mut b = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen_deferred("127.0.0.1:0", |m, conn| {
+                  conn.join_room(m)?;
+                  conn.send("Joined".to_string())?;
+                  Ok(())
+              })
+              .unwrap();
+
+        a.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        b.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+        assert_eq!(a.request("lobby".to_string(), Duration::from_secs(5)).unwrap().as_deref(), Some("Joined"));
+        assert_eq!(b.request("games".to_string(), Duration::from_secs(5)).unwrap().as_deref(), Some("Joined"));
+        let ids: Vec<u64> = server.connections().iter().map(|c| c.id).collect();
+        assert_eq!(server.room_members("lobby").len(), 1);
+
+        assert_eq!(server.send_to_room("lobby", "Hello lobby".to_string()), 1);
+        assert_eq!(a.read().unwrap(), "Hello lobby");
+        assert!(b.read_timeout(Duration::from_millis(100)).unwrap().is_none());
+
+        for id in &ids
+        {
+            assert!(server.join_room(*id, "all"));
+        }
+        assert_eq!(server.room_members("all"), ids);
+        assert!(!server.join_room(u64::MAX, "all"));
+        assert!(server.leave_room(ids[0], "all"));
+        assert!(!server.leave_room(ids[0], "all"));
+        assert!(!server.connection(ids[0]).unwrap().rooms.contains("all"));
+        assert_eq!(server.send_to_room("all", "Hello all".to_string()), 1);
+        assert_eq!(server.send_to_room("empty", "Hello nobody".to_string()), 0);
+
+        let b_id = server.room_members("games")[0];
+        b.close().unwrap();
+        while server.connection_count() > 1
+        {
+            std::thread::yield_now()
+        }
+        assert!(server.room_members("games").is_empty());
+        assert!(!server.room_members("all").contains(&b_id));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn overloaded_server_answers_busy()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { max_connections: Some(1),
+                                                                              ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+        wait_for_connections(&server, 1);
+
+        let mut rejected = SimpleSockleClient::new();
+        let err = rejected.connect(&addr.1)
+                          .unwrap_err()
+                          .downcast::<SimpleSockleError>()
+                          .unwrap();
+        assert!(matches!(err, SimpleSockleError::ServerBusy { retry_after: Some(d) } if d == Duration::from_secs(1)));
+        assert!(err.is_recoverable());
+
+        s.close().unwrap();
+        while server.connection_count() > 0
+        {
+            std::thread::yield_now()
+        }
+        rejected.connect(&addr.1).unwrap();
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stalled_rejections_do_not_hold_up_accepts()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { max_connections: Some(1),
+                                                                              ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+        wait_for_connections(&server, 1);
+        // Turned away while overloaded, but never send the upgrade request
+        let _stalled = (0..3).map(|_| std::net::TcpStream::connect(&addr.0).unwrap())
+                             .collect::<Vec<_>>();
+        std::thread::sleep(Duration::from_millis(100));
+
+        s.close().unwrap();
+        while server.connection_count() > 0
+        {
+            std::thread::yield_now()
+        }
+        let started = Instant::now();
+        let mut c = SimpleSockleClient::new();
+        c.connect(&addr.1).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let started = Instant::now();
+        server.shutdown().unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn reconnect_waits_for_server_disconnect()
+    {
+        use std::sync::{Arc, Mutex};
+
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let e = events.clone();
+        server.on_connect(move |info, _| e.lock().unwrap().push(format!("connect {}", info.id)));
+        let e = events.clone();
+        server.on_disconnect(move |info, _| {
+                  // Slow cleanup, the new connection must still wait for it
+                  std::thread::sleep(Duration::from_millis(50));
+                  e.lock().unwrap().push(format!("disconnect {}", info.id))
+              });
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+        wait_for_connections(&server, 1);
+        let first = server.connections()[0].id;
+        s.reconnect(&addr.1).unwrap();
+        assert_eq!(events.lock().unwrap()[..2], [format!("connect {first}"), format!("disconnect {first}")]);
+
+        wait_for_connections(&server, 1);
+        let second = server.connections()[0].id;
+        while events.lock().unwrap().len() < 3
+        {
+            std::thread::yield_now()
+        }
+        assert_eq!(events.lock().unwrap()[2], format!("connect {second}"));
+
+        let mut fresh = SimpleSockleClient::new();
+        fresh.reconnect(&addr.1).unwrap();
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn handler_panic_closes_only_its_connection()
+    {
+        use std::sync::{Arc, Mutex};
+        use tungstenite::protocol::frame::coding::CloseCode;
+
+        let _ = pretty_env_logger::try_init();
+        let mut a = SimpleSockleClient::new();
+        let mut b = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { worker_threads: Some(1),
+                                                                              ..Default::default() });
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let r = reasons.clone();
+        server.on_disconnect(move |_, reason| r.lock().unwrap().push(reason));
+        server.listen("127.0.0.1:0", |m, reply| {
+                  if m == "Panic"
+                  {
+                      panic!("Handler bug");
+                  }
+                  reply(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        a.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        b.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        a.write("Panic".to_string()).unwrap();
+        let err = a.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Error, .. }));
+
+        assert_eq!(b.request("Still here".to_string(), Duration::from_secs(5)).unwrap().as_deref(),
+                   Some("Still here"));
+        assert_eq!(*reasons.lock().unwrap(), [DisconnectReason::HandlerPanic]);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn hook_panics_close_only_their_connection()
+    {
+        use std::sync::{Arc, Mutex};
+        use tungstenite::protocol::frame::coding::CloseCode;
+
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { worker_threads: Some(1),
+                                                                              ..Default::default() });
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let r = reasons.clone();
+        server.on_disconnect(move |_, reason| {
+                  r.lock().unwrap().push(reason);
+                  panic!("Disconnect hook bug");
+              });
+        server.on_connect(|info, _| {
+                  if info.id == 0
+                  {
+                      panic!("Connect hook bug");
+                  }
+              });
+        server.on_outgoing(|_, m| {
+                  if m == "Panic"
+                  {
+                      panic!("Outgoing hook bug");
+                  }
+                  Some(m)
+              });
+        server.listen("127.0.0.1:0", |m, reply| {
+                  reply(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let mut a = SimpleSockleClient::new();
+        a.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        let err = a.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Error, .. }));
+
+        let mut b = SimpleSockleClient::new();
+        b.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        server.send("Panic".to_string());
+        let err = b.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Error, .. }));
+
+        let mut c = SimpleSockleClient::new();
+        c.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        assert_eq!(c.request("Still here".to_string(), Duration::from_secs(5)).unwrap().as_deref(),
+                   Some("Still here"));
+        assert_eq!(*reasons.lock().unwrap(),
+                   [DisconnectReason::HandlerPanic, DisconnectReason::HandlerPanic]);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn binary_message_closes_only_its_connection()
+    {
+        use tungstenite::protocol::frame::coding::CloseCode;
+
+        let _ = pretty_env_logger::try_init();
+        let mut b = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::with_config(SockleServerConfig { worker_threads: Some(1),
+                                                                              ..Default::default() });
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, reply| {
+                  reply(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let (mut a, _) = tungstenite::connect(&addr.1).unwrap();
+        b.connect(&addr.1).unwrap();
+        a.write_message(tungstenite::Message::Binary(vec![1, 2, 3]))
+         .unwrap();
+        assert!(matches!(a.read_message().unwrap(),
+                         tungstenite::Message::Close(Some(c)) if c.code == CloseCode::Unsupported));
+
+        assert_eq!(b.request("Still here".to_string(), Duration::from_secs(5)).unwrap().as_deref(),
+                   Some("Still here"));
+        let mut c = SimpleSockleClient::new();
+        c.connect(&addr.1).unwrap();
+        wait_for_connections(&server, 2);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn stats_report_connection_uptime()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut a = SimpleSockleClient::new();
+        let mut b = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+        assert!(server.stats().uptime.is_none());
+
+        a.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        std::thread::sleep(Duration::from_millis(50));
+        b.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+
+        let uptime = server.stats().uptime.unwrap();
+        assert!(uptime.max >= Duration::from_millis(50));
+        assert!(uptime.min < uptime.max);
+        assert!(uptime.min <= uptime.mean && uptime.mean <= uptime.max);
+        assert!(server.connections()[0].uptime() >= uptime.max);
+
+        server.shutdown().unwrap();
+        assert!(server.stats().uptime.is_none());
+    }
+
+    #[test]
+    fn read_frame_returns_pong()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        s.ping().unwrap();
+
+        assert_eq!(s.read_frame().unwrap(), SockleFrame::Pong(vec![0]));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn client_responds_to_server_ping()
+    {
+        use std::net::TcpListener;
+
+        let _ = pretty_env_logger::try_init();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            socket.write_message(tungstenite::Message::Ping(b"alive".to_vec()))
+                  .unwrap();
+            loop
+            {
+                if let tungstenite::Message::Pong(p) = socket.read_message().unwrap()
+                {
+                    assert_eq!(p, b"alive");
+                    break;
+                }
+            }
+            socket.write_message(tungstenite::Message::Text("Pong received".to_string()))
+                  .unwrap();
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&url).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Pong received");
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn echo_server()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn write_flush_writes_message()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write_flush("Test".to_string(), Duration::from_secs(1)).unwrap();
+        s.write_flush("Zero".to_string(), Duration::ZERO).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+        assert_eq!(s.read().unwrap(), "Zero");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn queued_messages_are_sent_on_flush()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        for i in 0..3
+        {
+            s.queue(i.to_string()).unwrap();
+        }
+        assert!(s.read_timeout(Duration::from_millis(50)).unwrap().is_none());
+
+        s.flush().unwrap();
+        s.write("3".to_string()).unwrap();
+
+        for i in 0..4
+        {
+            assert_eq!(s.read().unwrap(), i.to_string());
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn pending_write_bytes_tracks_unsent_data()
+    {
+        let _ = pretty_env_logger::try_init();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (done, done_r) = std::sync::mpsc::channel::<()>();
+        let peer = std::thread::spawn(move || {
+            // Accepts but never reads, so writes back up
+            let socket = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            let _ = done_r.recv();
+            drop(socket);
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&format!("ws://127.0.0.1:{port}")).unwrap();
+        assert_eq!(s.pending_write_bytes(), 0);
+
+        s.queue("Test".to_string()).unwrap();
+        assert!(s.pending_write_bytes() >= 4);
+        s.flush().unwrap();
+        assert_eq!(s.pending_write_bytes(), 0);
+
+        let msg = "x".repeat(1 << 20);
+        let mut timed_out = false;
+        for _ in 0..64
+        {
+            if s.write_flush(msg.clone(), Duration::from_millis(50)).is_err()
+            {
+                timed_out = true;
+                break;
+            }
+        }
+        assert!(timed_out);
+        assert!(s.pending_write_bytes() >= msg.len());
+
+        let err = s.write_flush(msg.clone(), Duration::ZERO).unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert_eq!(err.io_error_kind(), Some(std::io::ErrorKind::TimedOut));
+
+        done.send(()).unwrap();
+        peer.join().unwrap();
+    }
+
+    #[test]
+    fn request_returns_reply()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  if m != "Ignored"
+                  {
+                      f(m);
+                  }
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        assert_eq!(s.request("Test".to_string(), Duration::from_secs(1)).unwrap(),
+                   Some("Test".to_string()));
+        assert_eq!(s.request("Ignored".to_string(), Duration::from_millis(50)).unwrap(), None);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn empty_messages_can_be_ignored()
+    {
+        let _ = pretty_env_logger::try_init();
+        let config = SockleServerConfig { ignore_empty_messages: true,
+                                          ..Default::default() };
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::with_config(config);
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(format!("Got {m:?}"));
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write(String::new()).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Got \"Test\"");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn listen_str_borrows_message()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen_str("127.0.0.1:0", |m, f| {
+                  if m.starts_with("ping")
+                  {
+                      f(Reply::Text("pong".to_string()));
+                  }
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("ping 1".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "pong");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn on_reconnect_runs_after_reconnecting()
+    {
+        use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen_with_reply(&addr.0, |m, f| {
+                  if m == "Bye"
+                  {
+                      f(Reply::Close(CloseFrame { code:   CloseCode::Normal,
+                                                  reason: "Bye".into() }));
+                  }
+                  else
+                  {
+                      f(Reply::Text(m));
+                  }
+                  Ok(())
+              })
+              .unwrap();
+
+        s.set_on_reconnect(|c| c.write("Hello".to_string()));
+
+        s.connect(&addr.1).unwrap();
+        assert_eq!(s.request("First".to_string(), Duration::from_secs(1)).unwrap(),
+                   Some("First".to_string()));
+
+        s.write("Bye".to_string()).unwrap();
+        assert!(s.read().is_err());
+
+        s.connect(&addr.1).unwrap();
+        assert_eq!(s.read().unwrap(), "Hello");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reply_with_binary_and_close()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen_with_reply(&addr.0, |m, reply| {
+                  reply(Reply::Binary(m.into_bytes()));
+                  reply(Reply::Close(tungstenite::protocol::CloseFrame { code:   tungstenite::protocol::frame::coding::CloseCode::Policy,
+                                                                         reason: "Done".into() }));
+                  Ok(())
+              })
+              .unwrap();
+
+        let (mut socket, _) = tungstenite::connect(&addr.1).unwrap();
+
+        socket.write_message(tungstenite::Message::Text("Test".to_string()))
+              .unwrap();
+
+        assert_eq!(socket.read_message().unwrap(),
+                   tungstenite::Message::Binary(b"Test".to_vec()));
+        assert!(matches!(socket.read_message().unwrap(), tungstenite::Message::Close(Some(c)) if c.reason == "Done"));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn deferred_replies_are_sent_from_other_threads()
+    {
+        use tungstenite::protocol::frame::coding::CloseCode;
+
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen_deferred("127.0.0.1:0", |m, handle| {
+                  std::thread::spawn(move || {
+                      std::thread::sleep(Duration::from_millis(20));
+                      let reply = match m.as_str()
+                      {
+                          "Bye" => Reply::Close(tungstenite::protocol::CloseFrame { code:   CloseCode::Policy,
+                                                                                    reason: "Done".into() }),
+                          _ => Reply::Text(m.to_uppercase())
+                      };
+                      handle.reply(reply).unwrap();
+                  });
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "TEST");
+
+        s.write("Bye".to_string()).unwrap();
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Policy, .. }));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn subprotocol_is_negotiated_and_recorded()
+    {
+        let _ = pretty_env_logger::try_init();
+        let config = SockleServerConfig { subprotocols: Some(vec!["v1".to_string(), "v2".to_string()]),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        server.listen_deferred("127.0.0.1:0", |_, handle| {
+                  let protocol = handle.info().and_then(|i| i.subprotocol);
+                  handle.send(format!("{protocol:?}"))?;
+                  Ok(())
+              })
+              .unwrap();
+
+        let config = SockleClientConfig { headers: vec![("Sec-WebSocket-Protocol".to_string(), "v3, v2".to_string())],
+                                          ..Default::default() };
+        let mut s = SimpleSockleClient::with_config(config);
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Some(\"v2\")");
+        s.close().unwrap();
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "None");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn listen_blocking_returns_on_shutdown()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        let handle = server.handle();
+        let addr = listen_addr();
+        let listen_address = addr.0.clone();
+        let server_thread = std::thread::spawn(move || {
+            server.listen_blocking(&listen_address, |m, f| {
+                      f(m);
+                      Ok(())
+                  })
+                  .unwrap();
+        });
+
+        let mut s = SimpleSockleClient::new();
+        while s.connect(&addr.1).is_err()
+        {
+            std::thread::yield_now();
+        }
+
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+
+        handle.shutdown().unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn connections_are_registered_and_removed()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s1.connect(&addr.1).unwrap();
+        s2.connect(&addr.1).unwrap();
+
+        wait_for_connections(&server, 2);
+
+        let connections = server.connections();
+        assert_eq!(connections.len(), 2);
+        assert_ne!(connections[0].id, connections[1].id);
+        assert!(connections.iter().all(|c| c.peer_addr.ip().is_loopback()));
+        assert!(connections.iter().all(|c| c.local_addr.to_string() == addr.0));
+        assert_eq!(server.connection_count_by_family(), (2, 0));
+
+        drop(s1);
+
+        while server.connection_count() > 1
+        {
+            std::thread::yield_now()
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connections_are_counted_by_family()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut v4 = SimpleSockleClient::new();
+        let mut v6 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let port = listen_addr().0.rsplit(':').next().unwrap().to_string();
+        // Dual-stack, IPv4 clients arrive as IPv4-mapped addresses
+        server.listen(&format!("[::]:{port}"), |_, _| Ok(())).unwrap();
+
+        v4.connect(&format!("ws://127.0.0.1:{port}/")).unwrap();
+        v6.connect(&format!("ws://[::1]:{port}/")).unwrap();
+        wait_for_connections(&server, 2);
+
+        let connections = server.connections();
+        assert!(connections[0].peer_addr.is_ipv6() && !connections[0].is_ipv6());
+        assert!(connections[1].is_ipv6());
+        assert_eq!(server.connection_count_by_family(), (1, 1));
+
+        drop(v4);
+        while server.connection_count() > 1
+        {
+            std::thread::yield_now()
+        }
+        assert_eq!(server.connection_count_by_family(), (0, 1));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn last_message_at_is_updated_on_receive()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        let before = server.connections()[0].clone();
+        assert_eq!(before.last_message_at, before.connected_at);
+
+        s.write("Test".to_string()).unwrap();
+
+        while server.connection(before.id).unwrap().last_message_at == before.last_message_at
+        {
+            std::thread::yield_now()
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn as_raw_mut_writes_through_socket()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        assert!(s.as_raw_mut().is_none());
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.as_raw_mut()
+         .unwrap()
+         .write_message(tungstenite::Message::Text("Raw".to_string()))
+         .unwrap();
+        assert_eq!(s.read().unwrap(), "Raw");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connect_url_takes_parsed_url()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let mut url = Url::parse(&addr.1).unwrap();
+        url.query_pairs_mut().append_pair("token", "a b&c");
+        s.connect_url(url).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn try_clone_stream_shares_socket()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        let err = s.try_clone_stream().unwrap_err();
+        assert!(matches!(err, SimpleSockleError::SocketDisconnected));
+        s.connect(&addr.1).unwrap();
+        let stream = s.try_clone_stream().unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), s.transport().peer_addr().unwrap());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn split_client_reads_and_writes_from_separate_threads()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect(&addr.1).unwrap();
+
+        let (mut writer, mut reader) = s.split().unwrap();
+
+        let reader_thread = std::thread::spawn(move || (0..10).map(|_| reader.read().unwrap()).collect::<Vec<_>>());
+        for i in 0..10
+        {
+            writer.write(i.to_string()).unwrap();
+        }
+
+        assert_eq!(reader_thread.join().unwrap(),
+                   (0..10).map(|i| i.to_string()).collect::<Vec<_>>());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn split_client_keeps_peeked_and_queued_messages()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.on_connect(|_, handle| {
+                  handle.send("First".to_string()).unwrap();
+                  handle.send("Second".to_string()).unwrap();
+              });
+        server.listen_deferred(&addr.0, |m, handle| {
+                  match m.as_str()
+                  {
+                      "Bye" => handle.reply(SockleClose::policy("Done").into())?,
+                      _ => handle.send(m)?
+                  }
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect(&addr.1).unwrap();
+        while s.peek().unwrap().is_none()
+        {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(s.peek().unwrap(), Some("First"));
+        s.queue("Queued".to_string()).unwrap();
+
+        let (mut writer, mut reader) = s.split().unwrap();
+        writer.write("After".to_string()).unwrap();
+        for expected in ["First", "Second", "Queued", "After"]
+        {
+            assert_eq!(reader.read().unwrap(), expected);
+        }
+
+        // The server closes, only the reader answers
+        writer.write("Bye".to_string()).unwrap();
+        let err = reader.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Policy, .. }));
+        let err = writer.write("Late".to_string())
+                        .unwrap_err()
+                        .downcast::<SimpleSockleError>()
+                        .unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketDisconnected));
+        writer.close().unwrap();
+        assert!(server.wait_for_connections(0, Duration::from_secs(1)));
+
+        // The writer closes, the reader sees the server's acknowledgement
+        let mut s = SimpleSockleClient::new();
+        s.connect(&addr.1).unwrap();
+        let (mut writer, mut reader) = s.split().unwrap();
+        writer.close().unwrap();
+        let mut read = reader.read();
+        while let Ok(m) = read
+        {
+            assert!(m == "First" || m == "Second");
+            read = reader.read();
+        }
+        let err = read.unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Normal, .. }));
+        writer.close().unwrap();
+        assert!(server.wait_for_connections(0, Duration::from_secs(1)));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn into_channel_forwards_messages_until_closed()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        let messages = s.into_channel().unwrap();
+
+        server.send("First".to_string());
+        server.send("Second".to_string());
+
+        assert_eq!(messages.recv().unwrap().unwrap(), "First");
+        assert_eq!(messages.recv().unwrap().unwrap(), "Second");
+
+        server.shutdown().unwrap();
+
+        assert!(messages.recv().unwrap().is_err());
+        assert!(messages.recv().is_err());
+    }
+
+    #[test]
+    fn in_memory_connection_is_served()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let stream = server.connect_in_memory().unwrap();
+        let (mut ws, _) = tungstenite::client("ws://localhost/", stream).unwrap();
+        ws.write_message(tungstenite::Message::Text("Test".to_string()))
+          .unwrap();
+        assert_eq!(ws.read_message().unwrap().into_text().unwrap(), "Test");
+        assert_eq!(server.connection_count(), 1);
+
+        server.shutdown().unwrap();
+        assert!(server.connect_in_memory().is_err());
+    }
+
+    #[test]
+    fn client_connects_over_memory_stream()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let err = s.connect_stream("http://localhost/", server.connect_in_memory().unwrap())
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::InvalidUrl(_)));
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap())
+         .unwrap();
+        assert_eq!(s.request("Test".to_string(), Duration::from_secs(5)).unwrap().as_deref(),
+                   Some("Test"));
+        assert!(matches!(s.try_clone_stream(), Err(SimpleSockleError::StreamCloneUnsupported)));
+        s.close().unwrap();
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn on_handshake_adds_response_headers()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        server.on_handshake(|request, response| {
+                  assert_eq!(request.uri().path(), "/session");
+                  response.headers_mut()
+                          .insert("Set-Cookie", "session=abc".parse().unwrap());
+              });
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        let stream = server.connect_in_memory().unwrap();
+        let (_ws, response) = tungstenite::client("ws://localhost/session", stream).unwrap();
+        assert_eq!(response.headers().get("Set-Cookie").unwrap(), "session=abc");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcast()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s1.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s2.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 2);
+
+        server.send("Test".to_string());
+
+        assert_eq!(s1.read().unwrap(), "Test");
+        assert_eq!(s2.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn high_priority_messages_overtake_queued_ones()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let (started, started_r) = std::sync::mpsc::channel();
+        let started = std::sync::Mutex::new(started);
+        server.listen("127.0.0.1:0", move |_, _| {
+                  // Holds up the connection so broadcasts queue behind it
+                  started.lock().unwrap().send(()).unwrap();
+                  std::thread::sleep(Duration::from_millis(200));
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s.write("Block".to_string()).unwrap();
+        started_r.recv().unwrap();
+
+        server.send("0".to_string());
+        server.send("1".to_string());
+        server.send_with_priority("Urgent".to_string(), Priority::High);
+
+        assert_eq!(s.read().unwrap(), "Urgent");
+        assert_eq!(s.read().unwrap(), "0");
+        assert_eq!(s.read().unwrap(), "1");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn send_where_only_reaches_matching_connections()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s1.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s2.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+
+        let first = server.connections()[0].id;
+        assert_eq!(server.send_where(|c| c.id != first, "Test".to_string()), 1);
+
+        assert_eq!(s2.read().unwrap(), "Test");
+        assert!(s1.read_timeout(Duration::from_millis(50)).unwrap().is_none());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connection_handle_sends_to_one_client()
+    {
+        use std::sync::{Arc, Mutex};
+
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let handles = Arc::new(Mutex::new(Vec::new()));
+        let handles_c = handles.clone();
+        server.on_connect(move |info, handle| {
+                  assert_eq!(info.id, handle.id());
+                  handles_c.lock().unwrap().push(handle);
+              });
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s1.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s2.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+
+        let second = handles.lock().unwrap()[1].clone();
+        second.send("Test".to_string()).unwrap();
 
-        assert_eq!(s1.read().unwrap(), "Test");
         assert_eq!(s2.read().unwrap(), "Test");
+        assert!(s1.read_timeout(Duration::from_millis(50)).unwrap().is_none());
+
+        server.shutdown().unwrap();
+
+        assert!(matches!(second.send("Test".to_string()), Err(SimpleSockleError::SocketDisconnected)));
+    }
+
+    #[test]
+    fn handler_error_message_is_sent_before_close()
+    {
+        use tungstenite::protocol::frame::coding::CloseCode;
+
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Err(anyhow::anyhow!("Bad request"))).unwrap();
+        server.on_handler_error(|e| Some(format!("{{\"error\": \"{e}\"}}")));
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), r#"{"error": "Bad request"}"#);
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Error, .. }));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn labels_select_connections()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+        server.on_connect(|info, handle| {
+                  let tier = if info.id == 0 { "pro" } else { "free" };
+                  handle.set_label("tier", tier).unwrap();
+              });
+
+        s1.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s2.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+
+        let connections = server.connections();
+        assert_eq!(connections[0].labels["tier"], "pro");
+        assert_eq!(connections[1].labels["tier"], "free");
+        let sent = server.send_where(|i| i.labels.get("tier").is_some_and(|t| t == "pro"), "Pro".to_string());
+        assert_eq!(sent, 1);
+        assert_eq!(s1.read().unwrap(), "Pro");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcast_await_acks_counts_reading_clients()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s1.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s2.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+        let r1 = s1.into_channel().unwrap();
+        let r2 = s2.into_channel().unwrap();
+
+        assert_eq!(server.broadcast_await_acks("Test".to_string(), 2, Duration::from_secs(5)), 2);
+        assert_eq!(r1.recv().unwrap().unwrap(), "Test");
+        assert_eq!(r2.recv().unwrap().unwrap(), "Test");
+        assert_eq!(server.broadcast_await_acks("Test".to_string(), 3, Duration::from_millis(100)),
+                   2);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcasts_survive_full_socket_buffer()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let config = SockleServerConfig { send_buffer_size: Some(4096),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+        wait_for_connections(&server, 1);
+
+        // Far more than the socket buffers hold while the client isn't reading
+        let msg = "x".repeat(64 << 10);
+        for _ in 0..50
+        {
+            server.send(msg.clone());
+        }
+        std::thread::sleep(Duration::from_millis(200));
+        for _ in 0..50
+        {
+            assert_eq!(s.read().unwrap().len(), msg.len());
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn on_outgoing_rewrites_and_filters_broadcasts()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s1 = SimpleSockleClient::new();
+        let mut s2 = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s1.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s2.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+
+        let first = server.connections()[0].id;
+        server.on_outgoing(move |info, msg| (info.id != first).then(|| format!("{}: {msg}", info.id)));
+        server.send("Test".to_string());
+
+        assert_eq!(s2.read().unwrap(), format!("{}: Test", first + 1));
+        assert!(s1.read_timeout(Duration::from_millis(50)).unwrap().is_none());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcasts_beyond_queue_limit_are_dropped()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let config = SockleServerConfig { worker_threads: Some(1),
+                                          broadcast_queue_limit: Some(1),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        let (busy, busy_r) = std::sync::mpsc::channel();
+        server.listen("127.0.0.1:0", move |_, _| {
+                  busy.send(()).unwrap();
+                  std::thread::sleep(Duration::from_millis(200));
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+
+        // The worker is stuck in the handler, so nothing is taken off the queue
+        s.write("Block".to_string()).unwrap();
+        busy_r.recv().unwrap();
+        assert_eq!(server.send_where(|_| true, "1".to_string()), 1);
+        assert_eq!(server.send_where(|_| true, "2".to_string()), 0);
+        assert_eq!(server.send_where(|_| true, "3".to_string()), 0);
+        assert_eq!(server.connections()[0].dropped, 2);
+
+        assert_eq!(s.read().unwrap(), "1");
+        assert!(s.read_timeout(Duration::from_millis(50)).unwrap().is_none());
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn slow_reader_does_not_stall_its_worker()
+    {
+        let _ = pretty_env_logger::try_init();
+        let budget = 4 << 20;
+        let config = SockleServerConfig { worker_threads: Some(1),
+                                          send_buffer_size: Some(4096),
+                                          broadcast_queue_limit: Some(4),
+                                          max_queued_bytes: Some(budget),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, reply| {
+                  reply(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        // Never reads, so its socket stops taking writes
+        let (_slow, _) = tungstenite::connect(&addr.1).unwrap();
+        let mut s = SimpleSockleClient::new();
+        s.connect(&addr.1).unwrap();
+        wait_for_connections(&server, 2);
+
+        // Fill the slow client's socket buffers until writes to it would
+        // block, after which its backlog counts against the limits
+        let payload = "x".repeat(256 * 1024);
+        let mut dropped = 0;
+        for _ in 0..200
+        {
+            server.send_where(|i| i.id == 0, payload.clone());
+            assert!(server.stats().queued_bytes <= budget);
+            dropped = server.connection(0).unwrap().dropped;
+            if dropped > 0
+            {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(dropped > 0);
+
+        let started = Instant::now();
+        for i in 0..20
+        {
+            server.send(i.to_string());
+            assert_eq!(s.read().unwrap(), i.to_string());
+        }
+        assert_eq!(s.request("Test".to_string(), Duration::from_secs(1)).unwrap().as_deref(),
+                   Some("Test"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcasts_over_byte_budget_are_dropped()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let config = SockleServerConfig { worker_threads: Some(1),
+                                          max_queued_bytes: Some(10),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        let (busy, busy_r) = std::sync::mpsc::channel();
+        server.listen("127.0.0.1:0", move |_, _| {
+                  busy.send(()).unwrap();
+                  std::thread::sleep(Duration::from_millis(200));
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+
+        s.write("Block".to_string()).unwrap();
+        busy_r.recv().unwrap();
+        assert_eq!(server.send_where(|_| true, "First".to_string()), 1);
+        assert_eq!(server.send_where(|_| true, "Second".to_string()), 0);
+        assert_eq!(server.stats().queued_bytes, 5);
+        assert_eq!(server.connections()[0].dropped, 1);
+
+        assert_eq!(s.read().unwrap(), "First");
+        assert_eq!(server.stats().queued_bytes, 0);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn message_rate_is_limited_across_clients()
+    {
+        let _ = pretty_env_logger::try_init();
+        let config = SockleServerConfig { max_message_rate: Some(20),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+        assert_eq!(server.stats().max_message_rate, Some(20));
+
+        let mut clients = [SimpleSockleClient::new(), SimpleSockleClient::new()];
+        for c in clients.iter_mut()
+        {
+            c.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        }
+        let start = Instant::now();
+        for i in 0..15
+        {
+            for c in clients.iter_mut()
+            {
+                c.write(i.to_string()).unwrap();
+            }
+        }
+        for c in clients.iter_mut()
+        {
+            for i in 0..15
+            {
+                assert_eq!(c.read().unwrap(), i.to_string());
+            }
+        }
+        // 20 go straight through, the other 10 at 20 a second
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert!(server.stats().rate_utilization > 0.5);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn listen_with_handler_routes_events()
+    {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Counter
+        {
+            messages: AtomicUsize,
+            events:   Arc<Mutex<Vec<String>>>
+        }
+
+        impl SockleHandler for Counter
+        {
+            fn on_connect(&self, info: &ConnectionInfo, _handle: ConnectionHandle)
+            {
+                self.events.lock().unwrap().push(format!("connect {}", info.id));
+            }
+
+            fn on_message(&self, _message: String, reply: Box<dyn Fn(Reply)>) -> anyhow::Result<()>
+            {
+                let count = self.messages.fetch_add(1, Ordering::SeqCst) + 1;
+                reply(Reply::Text(count.to_string()));
+                Ok(())
+            }
+
+            fn on_disconnect(&self, info: &ConnectionInfo, reason: DisconnectReason)
+            {
+                self.events.lock().unwrap().push(format!("disconnect {} {reason:?}", info.id));
+            }
+        }
+
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let counter = Counter::default();
+        let events = counter.events.clone();
+        server.listen_with_handler("127.0.0.1:0", counter).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        for i in 1..=2
+        {
+            s.write("Test".to_string()).unwrap();
+            assert_eq!(s.read().unwrap(), i.to_string());
+        }
+        s.close().unwrap();
+        while events.lock().unwrap().len() < 2
+        {
+            std::thread::yield_now()
+        }
+        assert_eq!(*events.lock().unwrap(), ["connect 0", "disconnect 0 ClientClose"]);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn handler_timeout_closes_connection_and_frees_worker()
+    {
+        use std::sync::{atomic::AtomicBool, Arc};
+        use tungstenite::protocol::frame::coding::CloseCode;
+
+        let _ = pretty_env_logger::try_init();
+        let mut stuck = SimpleSockleClient::new();
+        let mut s = SimpleSockleClient::new();
+        let config = SockleServerConfig { worker_threads: Some(1),
+                                          handler_timeout: Some(Duration::from_millis(50)),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        let release = Arc::new(AtomicBool::new(false));
+        let r = release.clone();
+        let (reasons, reasons_r) = std::sync::mpsc::channel();
+        let reasons = std::sync::Mutex::new(reasons);
+        server.on_disconnect(move |_, reason| reasons.lock().unwrap().send(reason).unwrap());
+        server.listen("127.0.0.1:0", move |m, f| {
+                  // Runs away until the end of the test
+                  while m == "Loop" && !r.load(Ordering::SeqCst)
+                  {
+                      std::thread::sleep(Duration::from_millis(1));
+                  }
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        stuck.connect_stream("ws://localhost/", server.connect_in_memory().unwrap())
+             .unwrap();
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 2);
+        assert_eq!(stuck.request("Fast".to_string(), Duration::from_secs(1)).unwrap().as_deref(),
+                   Some("Fast"));
+
+        let started = Instant::now();
+        stuck.write("Loop".to_string()).unwrap();
+        let err = stuck.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Policy, .. }));
+        assert_eq!(reasons_r.recv().unwrap(), DisconnectReason::HandlerTimeout);
+
+        // The worker shared with the runaway handler is free again
+        assert_eq!(s.request("Test".to_string(), Duration::from_secs(1)).unwrap().as_deref(),
+                   Some("Test"));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        release.store(true, Ordering::SeqCst);
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn listen_decoded_converts_messages()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen_decoded("127.0.0.1:0",
+                              str::parse::<i32>,
+                              |v: &i32| Ok::<_, std::fmt::Error>(v.to_string()),
+                              |n, reply| {
+                                  reply(n * 2);
+                                  Ok(())
+                              })
+              .unwrap();
+        server.on_decode_error(|message, _| Some(format!("Not a number: {message}")));
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("21".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "42");
+        s.write("abc".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Not a number: abc");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn events_report_connection_lifecycle()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        let events = server.events();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+        let local = s.transport().local_addr().unwrap();
+        s.write("Test".to_string()).unwrap();
+        s.close().unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(events.recv_timeout(timeout).unwrap(), ServerEvent::Connected(0, local));
+        assert_eq!(events.recv_timeout(timeout).unwrap(),
+                   ServerEvent::Message(0, "Test".to_string()));
+        assert_eq!(events.recv_timeout(timeout).unwrap(),
+                   ServerEvent::Disconnected(0, DisconnectReason::ClientClose));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn on_disconnect_reports_reason()
+    {
+        use std::sync::{Arc, Mutex};
+
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+        let reasons = Arc::new(Mutex::new(Vec::new()));
+        let reasons_c = reasons.clone();
+        server.on_disconnect(move |_, reason| reasons_c.lock().unwrap().push(reason));
+        let wait_for_reasons = |count| {
+            while reasons.lock().unwrap().len() < count
+            {
+                std::thread::yield_now()
+            }
+        };
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s.close().unwrap();
+        wait_for_reasons(1);
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        drop(s);
+        wait_for_reasons(2);
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        server.shutdown().unwrap();
+
+        assert_eq!(*reasons.lock().unwrap(),
+                   [DisconnectReason::ClientClose,
+                    DisconnectReason::IoError,
+                    DisconnectReason::ServerShutdown]);
+    }
+
+    #[test]
+    fn server_threads_use_configured_name()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let config = SockleServerConfig { thread_name_prefix: "Test".to_string(),
+                                          thread_stack_size: Some(256 << 10),
+                                          ..Default::default() };
+        let mut server = SimpleSockleServer::with_config(config);
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, f| {
+                  f(std::thread::current().name().unwrap_or_default().to_string());
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect(&addr.1).unwrap();
+        s.write("Name".to_string()).unwrap();
+        assert!(s.read().unwrap().starts_with("Test Worker "));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn listen_from_accepts_on_given_listener()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        server.listen_from(listener, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect(&url).unwrap();
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn close_returns_once_server_acknowledges()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        let start = Instant::now();
+        s.close().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        while server.connection_count() > 0
+        {
+            std::thread::yield_now()
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn close_write_keeps_reading_until_server_closes()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        assert_eq!(s.ready_state(), ReadyState::Open);
+        s.write("0".to_string()).unwrap();
+        s.queue("1".to_string()).unwrap();
+        s.close_write().unwrap();
+        assert_eq!(s.ready_state(), ReadyState::Closing);
+
+        let err = s.write("2".to_string())
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::WriteClosed));
+
+        assert_eq!(s.read().unwrap(), "0");
+        assert_eq!(s.read().unwrap(), "1");
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { .. }));
+        assert_eq!(s.ready_state(), ReadyState::Closed);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_cancel_token_interrupts_read()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        let token = s.read_cancel_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::Cancelled));
+        canceller.join().unwrap();
+
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_after_cancel_blocks_without_timeout()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  std::thread::sleep(Duration::from_millis(200));
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        let token = s.read_cancel_token();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::Cancelled));
+        // With the token gone reads no longer wake up to poll it
+        canceller.join().unwrap();
+
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn drain_closes_idle_connections_and_stops_accepting()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        server.drain(Duration::from_secs(5)).unwrap();
+
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(err.is_recoverable());
+        assert!(SimpleSockleClient::new().connect(&addr.1).is_err());
+    }
+
+    #[test]
+    fn drain_lets_in_flight_exchange_finish()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut busy = SimpleSockleClient::new();
+        let mut idle = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let (handled, handled_r) = std::sync::mpsc::channel();
+        let handled = std::sync::Mutex::new(handled);
+        server.listen_deferred("127.0.0.1:0", move |m, handle| {
+                  handled.lock().unwrap().send(()).unwrap();
+                  std::thread::spawn(move || {
+                      std::thread::sleep(Duration::from_millis(200));
+                      handle.reply(Reply::Text(m)).unwrap();
+                  });
+                  Ok(())
+              })
+              .unwrap();
+
+        busy.connect_stream("ws://localhost/", server.connect_in_memory().unwrap())
+            .unwrap();
+        idle.connect_stream("ws://localhost/", server.connect_in_memory().unwrap())
+            .unwrap();
+        wait_for_connections(&server, 2);
+        busy.write("Request".to_string()).unwrap();
+        handled_r.recv().unwrap();
+
+        let started = Instant::now();
+        server.drain(Duration::from_secs(5)).unwrap();
+        assert!(server.connect_in_memory().is_err());
+
+        assert!(idle.read().is_err());
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert_eq!(busy.read().unwrap(), "Request");
+        let err = busy.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Away, .. }));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn drain_closes_connections_whose_handler_returned()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let (handled, handled_r) = std::sync::mpsc::channel();
+        let handled = std::sync::Mutex::new(handled);
+        server.listen("127.0.0.1:0", move |_, _| {
+                  handled.lock().unwrap().send(()).unwrap();
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        wait_for_connections(&server, 1);
+        s.write("No reply".to_string()).unwrap();
+        handled_r.recv().unwrap();
+
+        let started = Instant::now();
+        server.drain(Duration::from_secs(5)).unwrap();
+
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { code: CloseCode::Away, .. }));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn shutdown_is_idempotent()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+
+        server.shutdown().unwrap();
+
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        server.shutdown().unwrap();
+        server.shutdown().unwrap();
+        server.handle().shutdown().unwrap();
+    }
+
+    #[test]
+    fn dropping_server_releases_port_and_closes_connections()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        s.connect(&addr.1).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        drop(server);
+
+        assert!(s.read().is_err());
+        assert!(std::net::TcpListener::bind(&addr.0).is_ok());
+    }
+
+    #[test]
+    fn shutdown_waits_for_connections_to_close()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        server.shutdown().unwrap();
+
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn server_shutdown_is_not_recoverable()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        server.shutdown().unwrap();
+
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketClosed { .. }));
+        assert!(!err.is_recoverable());
+    }
+
+    #[test]
+    fn more_connections_than_workers()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        let count = std::thread::available_parallelism().unwrap().get() * 3;
+        let mut clients: Vec<SimpleSockleClient> = (0..count).map(|_| SimpleSockleClient::new()).collect();
+        for c in clients.iter_mut()
+        {
+            c.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        }
+
+        wait_for_connections(&server, count);
+
+        for (i, c) in clients.iter_mut().enumerate()
+        {
+            c.write(i.to_string()).unwrap();
+        }
+        for (i, c) in clients.iter_mut().enumerate()
+        {
+            assert_eq!(c.read().unwrap(), i.to_string());
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn broadcast_binary()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |_, _| Ok(())).unwrap();
+
+        let (mut socket, _) = tungstenite::connect(&addr.1).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        server.send_binary(vec![1, 2, 3]);
+
+        assert_eq!(socket.read_message().unwrap(),
+                   tungstenite::Message::Binary(vec![1, 2, 3]));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn queue_fails_when_send_buffer_full()
+    {
+        let _ = pretty_env_logger::try_init();
+        let config = SockleClientConfig { max_send_queue: Some(2),
+                                          ..Default::default() };
+        let mut s = SimpleSockleClient::with_config(config);
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        s.queue("0".to_string()).unwrap();
+        s.queue("1".to_string()).unwrap();
+        let err = s.queue("2".to_string())
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::SendBufferFull));
+
+        s.flush().unwrap();
+        s.queue("2".to_string()).unwrap();
+        s.flush().unwrap();
+
+        for i in 0..3
+        {
+            assert_eq!(s.read().unwrap(), i.to_string());
+        }
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn byte_reader_concatenates_payloads()
+    {
+        use std::io::Read;
+
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write("abc".to_string()).unwrap();
+        s.write("def".to_string()).unwrap();
+
+        let mut buf = [0; 6];
+        s.byte_reader().read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcdef");
+
+        server.shutdown().unwrap();
+        let mut rest = Vec::new();
+        assert_eq!(s.byte_reader().read_to_end(&mut rest).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_into_replaces_buffer_contents()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        let mut buf = String::with_capacity(64);
+        s.write("First message".to_string()).unwrap();
+        s.read_into(&mut buf).unwrap();
+        assert_eq!(buf, "First message");
+
+        let capacity = buf.capacity();
+        s.write("Second".to_string()).unwrap();
+        s.read_into(&mut buf).unwrap();
+        assert_eq!(buf, "Second");
+        assert_eq!(buf.capacity(), capacity);
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn read_timeout_waits_for_full_timeout()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        assert!(s.read_timeout(Duration::ZERO).unwrap().is_none());
+        let start = Instant::now();
+        assert!(s.read_timeout(Duration::from_millis(100)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn encoded_messages_round_trip()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+        s.write_encoded(&42, |v| Ok::<_, std::fmt::Error>(v.to_string()))
+         .unwrap();
+        assert_eq!(s.read_decoded(str::parse::<i32>).unwrap(), 42);
+
+        s.write("Not a number".to_string()).unwrap();
+        let err = s.read_decoded(str::parse::<i32>)
+                   .unwrap_err()
+                   .downcast::<SimpleSockleError>()
+                   .unwrap();
+        assert!(matches!(err, SimpleSockleError::Codec(_)));
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn invalid_utf8_can_be_skipped()
+    {
+        use tungstenite::protocol::frame::{coding::{Data, OpCode},
+                                           Frame};
+
+        let _ = pretty_env_logger::try_init();
+        // Sends invalid text then valid text to each client
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming()
+            {
+                let mut socket = tungstenite::accept(stream.unwrap()).unwrap();
+                let invalid = Frame::message(vec![0xff, 0xfe], OpCode::Data(Data::Text), true);
+                socket.write_message(tungstenite::Message::Frame(invalid)).unwrap();
+                socket.write_message(tungstenite::Message::Text("Ok".to_string()))
+                      .unwrap();
+                let _ = socket.read_message();
+            }
+        });
+
+        let mut s = SimpleSockleClient::new();
+        s.connect(&url).unwrap();
+        let err = s.read().unwrap_err().downcast::<SimpleSockleError>().unwrap();
+        assert!(matches!(err, SimpleSockleError::SocketError(tungstenite::Error::Utf8)));
+        assert_eq!(s.read().unwrap(), "Ok");
+        s.close().unwrap();
+
+        let config = SockleClientConfig { skip_invalid_utf8: true,
+                                          ..Default::default() };
+        let mut s = SimpleSockleClient::with_config(config);
+        s.connect(&url).unwrap();
+        assert_eq!(s.read().unwrap(), "Ok");
+    }
+
+    #[test]
+    fn read_bytes_returns_text_and_binary()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut s = SimpleSockleClient::new();
+        let mut server = SimpleSockleServer::new();
+        server.listen("127.0.0.1:0", |_, _| Ok(())).unwrap();
+
+        s.connect_stream("ws://localhost/", server.connect_in_memory().unwrap()).unwrap();
+
+        wait_for_connections(&server, 1);
+
+        server.send("Test".to_string());
+        server.send_binary(vec![0xff, 0x00]);
+
+        assert_eq!(s.read_bytes().unwrap(), b"Test");
+        assert_eq!(s.read_bytes().unwrap(), vec![0xff, 0x00]);
+
+        server.shutdown().unwrap();
+    }
+
+    /// Tunnels one connection like an HTTP CONNECT proxy, sending back the
+    /// request it received, returns the proxy's address
+    fn http_proxy() -> (String, std::sync::mpsc::Receiver<String>)
+    {
+        use std::{io::{Read, Write},
+                  net::{TcpListener, TcpStream}};
+
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = proxy.local_addr().unwrap().to_string();
+        let (requests, requests_r) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut client, _) = proxy.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n")
+            {
+                client.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let target = request.split_whitespace().nth(1).unwrap();
+            let mut upstream = TcpStream::connect(target).unwrap();
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            let _ = requests.send(request);
+
+            let mut client2 = client.try_clone().unwrap();
+            let mut upstream2 = upstream.try_clone().unwrap();
+            std::thread::spawn(move || std::io::copy(&mut upstream2, &mut client2));
+            let _ = std::io::copy(&mut client, &mut upstream);
+        });
+        (proxy_addr, requests_r)
+    }
+
+    #[test]
+    fn connect_via_http_proxy()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        let addr = listen_addr();
+        server.listen(&addr.0, |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+        let (proxy_addr, _) = http_proxy();
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_via_proxy(&addr.1, ProxyConfig::http(&proxy_addr))
+         .unwrap();
+
+        wait_for_connections(&server, 1);
+
+        s.write("Test".to_string()).unwrap();
+
+        assert_eq!(s.read().unwrap(), "Test");
+
+        server.shutdown().unwrap();
+    }
+
+    #[test]
+    fn connect_via_http_proxy_to_ipv6_target()
+    {
+        let _ = pretty_env_logger::try_init();
+        let mut server = SimpleSockleServer::new();
+        let port = listen_addr().0.rsplit(':').next().unwrap().to_string();
+        server.listen(&format!("[::1]:{port}"), |m, f| {
+                  f(m);
+                  Ok(())
+              })
+              .unwrap();
+        let (proxy_addr, requests) = http_proxy();
+
+        let mut s = SimpleSockleClient::new();
+        s.connect_via_proxy(&format!("ws://[::1]:{port}/"), ProxyConfig::http(&proxy_addr))
+         .unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with(&format!("CONNECT [::1]:{port} HTTP/1.1\r\n")), "{request}");
+        assert!(request.contains(&format!("\r\nHost: [::1]:{port}\r\n")), "{request}");
+        s.write("Test".to_string()).unwrap();
+        assert_eq!(s.read().unwrap(), "Test");
 
         server.shutdown().unwrap();
     }
+
+    #[test]
+    fn socks5_proxy_rejects_other_protocol_versions()
+    {
+        use std::io::{Read, Write};
+
+        let _ = pretty_env_logger::try_init();
+        // Method selection reply from a SOCKS4 or HTTP peer, then a connect
+        // reply with the wrong version after a valid method selection
+        let replies: [&[u8]; 2] = [b"HTTP/1.1 400 Bad Request\r\n\r\n",
+                                   &[0x05, 0x00, 0x04, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 80]];
+        for reply in replies
+        {
+            let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let proxy_addr = proxy.local_addr().unwrap().to_string();
+            let peer = std::thread::spawn(move || {
+                let (mut client, _) = proxy.accept().unwrap();
+                let mut greeting = [0u8; 3];
+                client.read_exact(&mut greeting).unwrap();
+                client.write_all(reply).unwrap();
+                let _ = client.read(&mut [0u8; 64]);
+            });
+
+            let mut s = SimpleSockleClient::new();
+            let err = s.connect_via_proxy("ws://127.0.0.1:1/", ProxyConfig::socks5(&proxy_addr))
+                       .unwrap_err()
+                       .downcast::<SimpleSockleError>()
+                       .unwrap();
+
+            assert!(matches!(err, SimpleSockleError::ProxyError(ref m) if m.contains("SOCKS version")), "{err}");
+            peer.join().unwrap();
+        }
+    }
 }
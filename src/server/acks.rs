@@ -0,0 +1,62 @@
+use super::*;
+use std::{collections::HashMap,
+          sync::{atomic::AtomicU64, Condvar}};
+
+/// Counts acknowledgements of broadcasts sent by `broadcast_await_acks`
+///
+/// Each such broadcast is followed by a ping carrying its correlation id,
+/// a client's pong echoing it back is its acknowledgement.
+#[derive(Default)]
+pub(crate) struct Acks
+{
+    next_id: AtomicU64,
+    waiting: Mutex<HashMap<u64, usize>>,
+    acked:   Condvar
+}
+
+impl Acks
+{
+    /// Starts counting acknowledgements for a new broadcast, returning its
+    /// correlation id
+    pub(crate) fn start(&self) -> u64
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.waiting.lock().unwrap().insert(id, 0);
+        id
+    }
+
+    /// Records a pong, ignoring those that aren't for a broadcast being
+    /// waited on
+    pub(crate) fn ack(&self, payload: &[u8])
+    {
+        let id = match payload.try_into()
+        {
+            Ok(id) => u64::from_be_bytes(id),
+            Err(_) => return
+        };
+        if let Some(count) = self.waiting.lock().unwrap().get_mut(&id)
+        {
+            *count += 1;
+            self.acked.notify_all();
+        }
+    }
+
+    /// Waits until expected acknowledgements arrive or timeout elapses,
+    /// returning how many did
+    pub(crate) fn wait(&self, id: u64, expected: usize, timeout: Duration) -> usize
+    {
+        let deadline = Instant::now() + timeout;
+        let mut waiting = self.waiting.lock().unwrap();
+        loop
+        {
+            let count = waiting[&id];
+            let now = Instant::now();
+            if count >= expected || now >= deadline
+            {
+                waiting.remove(&id);
+                return count;
+            }
+            waiting = self.acked.wait_timeout(waiting, deadline - now).unwrap().0;
+        }
+    }
+}
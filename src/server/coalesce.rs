@@ -0,0 +1,111 @@
+use crate::SockleTransport;
+use std::{io::{Read, Write},
+          net::SocketAddr,
+          sync::{atomic::{AtomicBool, Ordering},
+                 Arc},
+          time::Duration};
+
+/// Transport that holds back writes while corked, see
+/// `SockleServerConfig::coalesce_window`
+///
+/// tungstenite writes and flushes every frame on its own, buffering them
+/// here lets the frames of a burst go out in one write once uncorked.
+pub(crate) struct CoalescingStream
+{
+    inner:  Box<dyn SockleTransport>,
+    held:   Vec<u8>,
+    corked: Arc<AtomicBool>
+}
+
+impl CoalescingStream
+{
+    /// Wraps inner, returning the flag that corks it
+    pub(crate) fn new(inner: Box<dyn SockleTransport>) -> (Self, Arc<AtomicBool>)
+    {
+        let corked = Arc::new(AtomicBool::new(false));
+        (Self { inner,
+                held: Vec::new(),
+                corked: corked.clone() },
+         corked)
+    }
+
+    fn is_corked(&self) -> bool
+    {
+        self.corked.load(Ordering::Relaxed)
+    }
+
+    /// Writes out what was held back, keeping whatever the socket won't take
+    fn write_held(&mut self) -> std::io::Result<()>
+    {
+        while !self.held.is_empty()
+        {
+            let len = self.inner.write(&self.held)?;
+            if len == 0
+            {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            self.held.drain(..len);
+        }
+        Ok(())
+    }
+}
+
+impl Read for CoalescingStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for CoalescingStream
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        if self.is_corked()
+        {
+            self.held.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        self.write_held()?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        if self.is_corked()
+        {
+            return Ok(());
+        }
+        self.write_held()?;
+        self.inner.flush()
+    }
+}
+
+impl SockleTransport for CoalescingStream
+{
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>
+    {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        self.inner.local_addr()
+    }
+}
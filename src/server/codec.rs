@@ -0,0 +1,74 @@
+use super::*;
+use crate::CodecError;
+
+impl SimpleSockleServer
+{
+    /// Same as listen, but messages are decoded into Req before reaching
+    /// the handler and its Resp replies encoded
+    ///
+    /// decode and encode are any conversion functions, e.g.
+    /// `serde_json::from_str` and `serde_json::to_string`. Messages that
+    /// fail to decode go to the `on_decode_error` hook, without one they are
+    /// treated as a handler error. Replies that fail to encode are logged
+    /// and dropped.
+    ///
+    /// Stands in for a `serde` gated `listen_json`, as the crate doesn't
+    /// depend on serde_json. That would be this with
+    /// `Req: DeserializeOwned` and `Resp: Serialize` and the serde_json
+    /// functions passed in, `on_decode_error` being its parse error hook.
+    pub fn listen_decoded<Req, Resp, D, DE, En, EE, F>(&mut self,
+                                                       listen_address: &str,
+                                                       decode: D,
+                                                       encode: En,
+                                                       on_message: F)
+                                                       -> Result<()>
+        where D: Fn(&str) -> Result<Req, DE> + Send + Sync + 'static,
+              DE: Into<CodecError>,
+              En: Fn(&Resp) -> Result<String, EE> + Send + Sync + 'static,
+              EE: std::fmt::Display,
+              F: Fn(Req, Box<dyn Fn(Resp)>) -> Result<()> + Send + Sync + 'static,
+              Resp: 'static
+    {
+        let shared = self.shared.clone();
+        let encode = Arc::new(encode);
+        self.listen_with_reply(listen_address, move |message, reply| {
+                let request = match decode(&message)
+                {
+                    Ok(request) => request,
+                    Err(e) =>
+                    {
+                        let e = e.into();
+                        let on_decode_error = shared.on_decode_error.read().unwrap().clone();
+                        return match on_decode_error
+                        {
+                            Some(f) =>
+                            {
+                                if let Some(text) = f(&message, &e)
+                                {
+                                    reply(Reply::Text(text));
+                                }
+                                Ok(())
+                            }
+                            None => Err(SimpleSockleError::Codec(e).into())
+                        };
+                    }
+                };
+                let encode = encode.clone();
+                on_message(request,
+                           Box::new(move |response| match encode(&response)
+                           {
+                               Ok(text) => reply(Reply::Text(text)),
+                               Err(e) => log::error!("Unable to encode reply, dropping it: {e}")
+                           }))
+            })
+    }
+
+    /// Calls f with the raw message and error when `listen_decoded` can't
+    /// decode a message, sending the text it returns as the reply
+    ///
+    /// The connection stays open. Replaces any previous hook.
+    pub fn on_decode_error<F: Fn(&str, &CodecError) -> Option<String> + Send + Sync + 'static>(&self, f: F)
+    {
+        *self.shared.on_decode_error.write().unwrap() = Some(Arc::new(f));
+    }
+}
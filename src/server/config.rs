@@ -0,0 +1,282 @@
+use std::{net::TcpStream, time::Duration};
+use tungstenite::{handshake::server::{ErrorResponse, Request, Response},
+                  http::{HeaderValue, StatusCode},
+                  protocol::WebSocketConfig};
+
+/// Settings for `SimpleSockleServer`, see `SimpleSockleServer::with_config`
+///
+/// Compression (permessage-deflate) isn't negotiated, the underlying
+/// websocket library doesn't support it. Neither does it allow sizing its
+/// internal read and write buffers, `recv_buffer_size` and
+/// `send_buffer_size` tune the OS socket buffers instead.
+#[derive(Clone, Debug)]
+pub struct SockleServerConfig
+{
+    /// How long idle threads wait before checking for new connections,
+    /// messages and broadcasts again, defaults to 15ms
+    ///
+    /// Lower values reduce latency when the server is quiet at the cost of
+    /// more wakeups and CPU usage, higher values save CPU but add up to this
+    /// much delay. Busy connections are serviced without waiting.
+    pub poll_interval:         Duration,
+    /// Number of threads serving connections, None uses one per core
+    pub worker_threads:        Option<usize>,
+    /// Connections beyond this are answered with 503 and a Retry-After
+    /// header instead of being upgraded, None for no limit
+    ///
+    /// Clients see `SimpleSockleError::ServerBusy`. The same happens while
+    /// `max_queued_bytes` is used up.
+    pub max_connections:       Option<usize>,
+    /// Largest incoming message allowed, defaults to 64MiB, None for no limit
+    pub max_message_size:      Option<usize>,
+    /// Sends a ping to each client at this interval, None to disable
+    pub keepalive_interval:    Option<Duration>,
+    /// Closes connections that haven't sent anything for this long, None to
+    /// disable
+    ///
+    /// Pongs count as activity, so pair with `keepalive_interval` to only
+    /// close unresponsive clients.
+    pub idle_timeout:          Option<Duration>,
+    /// Drops connections that don't complete the websocket upgrade within
+    /// this long of being accepted, defaults to 10s, None to wait
+    /// indefinitely
+    ///
+    /// The limit covers the whole upgrade, a client trickling the request
+    /// in a byte at a time is still dropped once it is up.
+    pub handshake_timeout:     Option<Duration>,
+    /// Origins allowed to connect, compared case-insensitively against the
+    /// Origin header, None allows any
+    ///
+    /// Mismatches are answered with 403. Requests without an Origin header
+    /// don't come from a browser and are always allowed.
+    pub allowed_origins:       Option<Vec<String>>,
+    /// Subprotocols the server speaks, in no particular order, None to not
+    /// negotiate one
+    ///
+    /// The first protocol in the client's Sec-WebSocket-Protocol header
+    /// that is in this list is agreed and sent back. Clients offering none
+    /// of them connect without a subprotocol. The outcome is in
+    /// `ConnectionInfo::subprotocol`, `SimpleSockleServer::on_handshake`
+    /// can override it.
+    pub subprotocols:          Option<Vec<String>>,
+    /// Broadcasts a client can have waiting before further ones are dropped
+    /// for it, None for no limit
+    ///
+    /// Keeps a client that can't keep up from buffering without bound.
+    /// Drops are counted in `ConnectionInfo::dropped`.
+    pub broadcast_queue_limit: Option<usize>,
+    /// Bytes all connections together can have waiting before broadcasts
+    /// start being dropped, None for no limit
+    ///
+    /// Each recipient counts the full message size. Once a broadcast
+    /// doesn't fit, the connections furthest behind miss it first. See
+    /// `SockleServer::stats` for the current total.
+    pub max_queued_bytes:      Option<usize>,
+    /// Closes connections whose message handler runs for longer than
+    /// this, None for no limit
+    ///
+    /// Handlers then run on a thread of their own while the worker waits
+    /// for them. Once the timeout passes the connection is closed with a
+    /// Policy close frame and the message is logged. The worker carries on
+    /// with its other connections, the handler is left running on its
+    /// thread, as it can't be stopped, and its replies are discarded.
+    pub handler_timeout:       Option<Duration>,
+    /// Skips the handler for empty text messages, defaults to false
+    ///
+    /// For clients that send empties to keep the connection alive, they
+    /// still count as activity for `idle_timeout`.
+    pub ignore_empty_messages: bool,
+    /// Messages per second handled across all clients, None for no limit
+    ///
+    /// Protects a shared downstream from bursts, up to a second's worth can
+    /// be handled at once. While the budget is used up connections stop
+    /// reading, so further messages wait in the socket buffers and clients
+    /// writing faster are slowed down by TCP. Enforced per worker without
+    /// coordination, so it can be overshot by a message per worker.
+    pub max_message_rate:      Option<u32>,
+    /// How long a connection holds back messages sent to it before writing
+    /// them out together, None to write each straight away
+    ///
+    /// Tungstenite writes every frame with a syscall of its own. With a
+    /// window, the first broadcast or `ConnectionHandle` send starts it and
+    /// everything written to the connection until it ends, replies
+    /// included, goes out in one write. Fewer syscalls and packets for
+    /// chatty connections, at the cost of up to this much added latency.
+    /// Keep it small, e.g. 1ms. Workers poll without sleeping while a
+    /// window is open.
+    pub coalesce_window:       Option<Duration>,
+    /// Sets TCP_NODELAY on accepted connections, disabling Nagle's algorithm
+    pub nodelay:               bool,
+    /// SO_RCVBUF size for accepted connections, None leaves the OS default
+    ///
+    /// Only applied on unix. The OS treats this as a hint: Linux doubles the
+    /// value and caps it at `net.core.rmem_max`, macOS caps it at
+    /// `kern.ipc.maxsockbuf`.
+    pub recv_buffer_size:      Option<usize>,
+    /// SO_SNDBUF size for accepted connections, None leaves the OS default
+    ///
+    /// Same platform caveats as `recv_buffer_size`, capped by
+    /// `net.core.wmem_max` on Linux.
+    pub send_buffer_size:      Option<usize>,
+    /// Start of the listen and worker thread names, defaults to
+    /// "Sockle Server"
+    pub thread_name_prefix:    String,
+    /// Stack size of the listen and worker threads in bytes, None uses the
+    /// std default (2MiB unless `RUST_MIN_STACK` is set)
+    ///
+    /// Handlers run on the worker threads, leave room for them.
+    pub thread_stack_size:     Option<usize>
+}
+
+impl Default for SockleServerConfig
+{
+    fn default() -> Self
+    {
+        Self { poll_interval:         Duration::from_millis(15),
+               worker_threads:        None,
+               max_connections:       None,
+               max_message_size:      Some(64 << 20),
+               keepalive_interval:    None,
+               idle_timeout:          None,
+               handshake_timeout:     Some(Duration::from_secs(10)),
+               allowed_origins:       None,
+               subprotocols:          None,
+               broadcast_queue_limit: None,
+               max_queued_bytes:      None,
+               handler_timeout:       None,
+               ignore_empty_messages: false,
+               max_message_rate:      None,
+               coalesce_window:       None,
+               nodelay:               false,
+               recv_buffer_size:      None,
+               send_buffer_size:      None,
+               thread_name_prefix:    "Sockle Server".to_string(),
+               thread_stack_size:     None }
+    }
+}
+
+impl SockleServerConfig
+{
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig
+    {
+        WebSocketConfig { max_message_size: self.max_message_size,
+                          ..Default::default() }
+    }
+
+    /// Builder for a server thread, named name after the prefix
+    pub(crate) fn thread_builder(&self, name: &str) -> std::thread::Builder
+    {
+        let builder = std::thread::Builder::new().name(format!("{} {name}", self.thread_name_prefix));
+        match self.thread_stack_size
+        {
+            Some(size) => builder.stack_size(size),
+            None => builder
+        }
+    }
+
+    /// Rejects the upgrade request if its Origin isn't allowed
+    pub(crate) fn check_origin(&self, request: &Request) -> Result<(), ErrorResponse>
+    {
+        let allowed = match &self.allowed_origins
+        {
+            Some(allowed) => allowed,
+            None => return Ok(())
+        };
+        let origin = match request.headers().get("Origin")
+        {
+            Some(origin) => origin.to_str().unwrap_or_default(),
+            None => return Ok(())
+        };
+        if allowed.iter().any(|a| a.eq_ignore_ascii_case(origin))
+        {
+            return Ok(());
+        }
+        log::warn!("Rejecting connection from disallowed origin {origin}");
+        let mut response = ErrorResponse::new(Some("Origin not allowed".to_string()));
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        Err(response)
+    }
+
+    /// Agrees the first subprotocol requested by the client that is also
+    /// configured, setting it on the response
+    pub(crate) fn negotiate_subprotocol(&self, request: &Request, response: &mut Response)
+    {
+        let supported = match &self.subprotocols
+        {
+            Some(supported) => supported,
+            None => return
+        };
+        let chosen = request.headers()
+                            .get_all("Sec-WebSocket-Protocol")
+                            .iter()
+                            .filter_map(|v| v.to_str().ok())
+                            .flat_map(|v| v.split(','))
+                            .map(str::trim)
+                            .find(|p| supported.iter().any(|s| s == p));
+        if let Some(value) = chosen.and_then(|p| HeaderValue::from_str(p).ok())
+        {
+            response.headers_mut().insert("Sec-WebSocket-Protocol", value);
+        }
+    }
+
+    /// Applies the socket options to a newly accepted stream
+    pub(crate) fn apply(&self, stream: &TcpStream) -> std::io::Result<()>
+    {
+        if self.nodelay
+        {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(size) = self.recv_buffer_size
+        {
+            set_buffer_size(stream, BufferKind::Recv, size)?;
+        }
+        if let Some(size) = self.send_buffer_size
+        {
+            set_buffer_size(stream, BufferKind::Send, size)?;
+        }
+        Ok(())
+    }
+}
+
+enum BufferKind
+{
+    Recv,
+    Send
+}
+
+#[cfg(unix)]
+fn set_buffer_size(stream: &TcpStream, kind: BufferKind, size: usize) -> std::io::Result<()>
+{
+    use std::os::unix::io::AsRawFd;
+
+    let option = match kind
+    {
+        BufferKind::Recv => libc::SO_RCVBUF,
+        BufferKind::Send => libc::SO_SNDBUF
+    };
+    let value = libc::c_int::try_from(size).unwrap_or(libc::c_int::MAX);
+    // SAFETY: the fd is owned by the stream for the duration of the call and
+    // value outlives it
+    let result = unsafe {
+        libc::setsockopt(stream.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         option,
+                         &value as *const libc::c_int as *const libc::c_void,
+                         std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result == 0
+    {
+        Ok(())
+    }
+    else
+    {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_buffer_size(_stream: &TcpStream, _kind: BufferKind, _size: usize) -> std::io::Result<()>
+{
+    log::warn!("Socket buffer sizes are only supported on unix, ignoring");
+    Ok(())
+}
@@ -0,0 +1,714 @@
+use super::*;
+use handshake::Upgraded;
+use registry::CtrlReceiver;
+use watchdog::Watchdog;
+use std::{cell::RefCell, io::Write, net::SocketAddr, panic::AssertUnwindSafe, rc::Rc};
+
+/// Longest a closing connection waits for its close frame to be written
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Logs with the connection's id and peer address in front, e.g.
+/// `[conn 3 127.0.0.1:40120] Connection closed: Idle`
+macro_rules! conn_log {
+    ($level:ident, $conn:expr, $($arg:tt)+) => {
+        log::$level!("[conn {} {}] {}", $conn.id, $conn.peer_addr, format_args!($($arg)+))
+    };
+}
+
+/// Result of polling a connection once
+pub(crate) enum ConnStatus
+{
+    /// Something was read or written
+    Busy,
+    /// Nothing to do
+    Idle,
+    /// Connection has ended and can be dropped
+    Closed
+}
+
+/// Why a connection ended, see `SimpleSockleServer::on_disconnect`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason
+{
+    /// Client sent a close frame
+    ClientClose,
+    /// Closed by `shutdown`, `drain` or dropping the server
+    ServerShutdown,
+    /// Nothing received within `SockleServerConfig::idle_timeout`
+    Idle,
+    /// Reading or writing the socket failed, including the client dropping
+    /// the connection without a close frame
+    IoError,
+    /// Client broke the websocket protocol, sent invalid UTF-8 or a message
+    /// over the size limit
+    ProtocolError,
+    /// The message handler returned an error
+    HandlerError,
+    /// The message handler or one of the server hooks panicked
+    HandlerPanic,
+    /// The message handler ran longer than
+    /// `SockleServerConfig::handler_timeout`
+    HandlerTimeout,
+    /// The message handler replied with `Reply::Close`, immediately or
+    /// through `ConnectionHandle::reply`
+    ServerClose
+}
+
+impl DisconnectReason
+{
+    pub(crate) fn from_error(e: &tungstenite::Error) -> Self
+    {
+        use tungstenite::error::{Error, ProtocolError};
+        match e
+        {
+            Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) => DisconnectReason::IoError,
+            Error::Protocol(_) | Error::Utf8 | Error::Capacity(_) => DisconnectReason::ProtocolError,
+            Error::ConnectionClosed | Error::AlreadyClosed => DisconnectReason::ClientClose,
+            _ => DisconnectReason::IoError
+        }
+    }
+
+    /// Whether a write failed only because the client had already gone,
+    /// part of normal churn rather than a fault
+    pub(crate) fn peer_gone(e: &tungstenite::Error) -> bool
+    {
+        use std::io::ErrorKind::{BrokenPipe, ConnectionAborted, ConnectionReset};
+        match e
+        {
+            tungstenite::Error::Io(e) => matches!(e.kind(), BrokenPipe | ConnectionReset | ConnectionAborted),
+            tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => true,
+            _ => false
+        }
+    }
+}
+
+/// Describes a caught panic from its payload
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str
+{
+    panic.downcast_ref::<&str>()
+         .copied()
+         .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+         .unwrap_or("unknown cause")
+}
+
+/// A client connection, lives on the worker thread that accepted it
+pub(crate) struct Conn
+{
+    id:              u64,
+    peer_addr:       SocketAddr,
+    shared:          Arc<Shared>,
+    socket:          tungstenite::WebSocket<Box<dyn SockleTransport>>,
+    ctrl:            CtrlReceiver,
+    handle:          ConnectionHandle,
+    on_message:      OnMessageFn,
+    /// Runs the handler under `SockleServerConfig::handler_timeout`, None
+    /// to run it directly
+    watchdog:        Option<Rc<Watchdog>>,
+    replies:         Rc<RefCell<Vec<Reply>>>,
+    config:          Arc<SockleServerConfig>,
+    last_message_at: Instant,
+    last_ping:       Instant,
+    drain_deadline:  Option<Instant>,
+    /// A deferred handler got a message and nothing has been sent back
+    /// since, keeps a draining connection open for the reply
+    unanswered:      bool,
+    /// A write would have blocked, frames are waiting in the socket's send
+    /// queue until a flush gets through
+    write_blocked:   bool,
+    /// Corks the socket's `CoalescingStream`, None if not coalescing
+    cork:            Option<Arc<AtomicBool>>,
+    /// When the socket was corked, writes since are held back
+    corked_at:       Option<Instant>,
+    /// Set by whichever path closes the connection, reported when dropped
+    reason:          Option<DisconnectReason>
+}
+
+impl Conn
+{
+    fn new(peer_addr: SocketAddr,
+           shared: Arc<Shared>,
+           socket: tungstenite::WebSocket<Box<dyn SockleTransport>>,
+           ctrl: CtrlReceiver,
+           handle: ConnectionHandle,
+           on_message: OnMessageFn,
+           config: Arc<SockleServerConfig>)
+           -> Conn
+    {
+        Self { id: handle.id(),
+               peer_addr,
+               shared,
+               socket,
+               ctrl,
+               handle,
+               on_message,
+               watchdog: None,
+               replies: Default::default(),
+               config,
+               last_message_at: Instant::now(),
+               last_ping: Instant::now(),
+               drain_deadline: None,
+               unanswered: false,
+               write_blocked: false,
+               cork: None,
+               corked_at: None,
+               reason: None }
+    }
+
+    /// Registers a connection that completed the websocket handshake
+    ///
+    /// Returns None if the server is shutting down
+    pub(crate) fn accept(upgraded: Upgraded,
+                         shared: &Arc<Shared>,
+                         on_message: OnMessageFn,
+                         watchdog: Option<Rc<Watchdog>>,
+                         config: Arc<SockleServerConfig>)
+                         -> Option<Conn>
+    {
+        let Upgraded { socket,
+                       peer_addr,
+                       local_addr,
+                       subprotocol,
+                       cork } = upgraded;
+        let (id, r) = shared.registry.register(peer_addr, local_addr, subprotocol);
+        let handle = shared.registry
+                           .handle(id)
+                           .expect("Connection was just registered");
+        let mut conn = Conn::new(peer_addr, shared.clone(), socket, r, handle, on_message, config);
+        conn.cork = cork;
+        conn.watchdog = watchdog;
+        conn_log!(info, conn, "Connected");
+        // Registered after a drain's broadcast, so drain along with the rest
+        conn.drain_deadline = *shared.drain_deadline.lock().unwrap();
+        if conn.drain_deadline.is_none() && shared.stopping.load(Ordering::SeqCst)
+        {
+            conn_log!(info, conn, "Shutting down, closing a client socket");
+            conn.close_socket(DisconnectReason::ServerShutdown,
+                              Some(CloseFrame { code:   CloseCode::Normal,
+                                                reason: "Server Shutdown".into() }));
+            return None;
+        }
+        let on_connect = shared.on_connect.read().unwrap().clone();
+        if let (Some(on_connect), Some(info)) = (on_connect, shared.registry.info(id))
+        {
+            if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| on_connect(&info, conn.handle.clone())))
+            {
+                conn.handler_panicked(panic_message(&*panic));
+                return None;
+            }
+        }
+        shared.events.emit(|| ServerEvent::Connected(id, peer_addr));
+        Some(conn)
+    }
+
+    /// Handles any pending incoming frame and ctrl message without blocking
+    ///
+    /// Panics in the handler or any hook it runs are contained to this
+    /// connection, which is closed, rather than unwinding the worker and
+    /// every other connection on it.
+    pub(crate) fn poll(&mut self) -> ConnStatus
+    {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| self.poll_inner()))
+        {
+            Ok(status) => status,
+            Err(panic) =>
+            {
+                self.handler_panicked(panic_message(&*panic));
+                ConnStatus::Closed
+            }
+        }
+    }
+
+    /// Closes the connection after a user callback panicked
+    fn handler_panicked(&mut self, what: &str)
+    {
+        conn_log!(error, self, "Handler panicked, closing client socket: {what}");
+        self.replies.borrow_mut().clear();
+        self.close_socket(DisconnectReason::HandlerPanic,
+                          Some(CloseFrame { code:   CloseCode::Error,
+                                            reason: "Handler Panicked".into() }));
+    }
+
+    fn poll_inner(&mut self) -> ConnStatus
+    {
+        let mut status = ConnStatus::Idle;
+
+        if let Err(e) = self.flush_blocked()
+        {
+            conn_log!(error, self, "Unable to write queued frames to socket: {e}");
+            self.reason = Some(DisconnectReason::from_error(&e));
+            return ConnStatus::Closed;
+        }
+
+        let throttled = self.config
+                            .max_message_rate
+                            .is_some_and(|rate| !self.shared.throttle.ready(rate));
+        match self.read_message(throttled)
+        {
+            Ok(msg) =>
+            {
+                if let (Message::Text(_), Some(rate)) = (&msg, self.config.max_message_rate)
+                {
+                    self.shared.throttle.take(rate);
+                }
+                self.last_message_at = Instant::now();
+                self.shared.registry.touch(self.id, self.last_message_at);
+                if !self.on_message(msg)
+                {
+                    return ConnStatus::Closed;
+                }
+                status = ConnStatus::Busy;
+            }
+            Err(tungstenite::error::Error::Io(e))
+                if matches!(e.kind(),
+                            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+            {}
+            Err(e) =>
+            {
+                conn_log!(error, self, "Error on client socket: {e}");
+                self.close_socket(DisconnectReason::from_error(&e),
+                                  Some(CloseFrame { code:   CloseCode::Error,
+                                                    reason: e.to_string().into() }));
+                return ConnStatus::Closed;
+            }
+        }
+        let ctrl = self.ctrl.try_recv();
+        let len = ctrl.as_ref().ok().and_then(SockleServerMessage::data_len).unwrap_or_default();
+        match ctrl
+        {
+            Ok(SockleServerMessage::Send(msg, _)) =>
+            {
+                conn_log!(debug, self, "Received Send ctrl message on socket, writing to client");
+                self.cork();
+                if let Some(msg) = self.intercept(msg.to_string())
+                {
+                    if !self.write_broadcast(Message::Text(msg))
+                    {
+                        return ConnStatus::Closed;
+                    }
+                    self.hold_if_blocked(len);
+                }
+                status = ConnStatus::Busy;
+            }
+            Ok(SockleServerMessage::SendWithAck(msg, ack)) =>
+            {
+                conn_log!(debug, self, "Received SendWithAck ctrl message on socket, writing to client");
+                self.cork();
+                if let Some(msg) = self.intercept(msg.to_string())
+                {
+                    if !self.write_broadcast(Message::Text(msg))
+                       || !self.write_broadcast(Message::Ping(ack.to_be_bytes().to_vec()))
+                    {
+                        return ConnStatus::Closed;
+                    }
+                    self.hold_if_blocked(len);
+                }
+                status = ConnStatus::Busy;
+            }
+            Ok(SockleServerMessage::SendBinary(data)) =>
+            {
+                conn_log!(debug, self, "Received SendBinary ctrl message on socket, writing to client");
+                self.cork();
+                if !self.write_broadcast(Message::Binary(data.to_vec()))
+                {
+                    return ConnStatus::Closed;
+                }
+                self.hold_if_blocked(len);
+                status = ConnStatus::Busy;
+            }
+            Ok(SockleServerMessage::Close(cf)) =>
+            {
+                conn_log!(debug, self, "Received Close ctrl message on socket, closing client socket");
+                self.close_socket(DisconnectReason::ServerClose, Some(cf));
+                return ConnStatus::Closed;
+            }
+            Ok(SockleServerMessage::Drain(deadline)) =>
+            {
+                conn_log!(debug, self, "Draining, closing client socket once idle");
+                self.drain_deadline = Some(deadline);
+            }
+            Ok(SockleServerMessage::Shutdown) =>
+            {
+                conn_log!(info, self, "Shutting down, closing a client socket");
+                self.close_socket(DisconnectReason::ServerShutdown,
+                                  Some(CloseFrame { code:   CloseCode::Normal,
+                                                    reason: "Server Shutdown".into() }));
+                return ConnStatus::Closed;
+            }
+            Err(TryRecvError::Disconnected) =>
+            {
+                conn_log!(warn, self, "Client ctrl channel disconnected, closing client socket");
+                self.close_socket(DisconnectReason::ServerShutdown,
+                                  Some(CloseFrame { code:   CloseCode::Normal,
+                                                    reason: "Server Error".into() }));
+                return ConnStatus::Closed;
+            }
+            Err(TryRecvError::Empty) =>
+            {}
+        }
+
+        if let (Some(corked_at), Some(window)) = (self.corked_at, self.config.coalesce_window)
+        {
+            if corked_at.elapsed() < window
+            {
+                // Keep the worker polling so the window isn't overrun
+                status = ConnStatus::Busy;
+            }
+            else if let Err(e) = self.uncork()
+            {
+                conn_log!(error, self, "Unable to write coalesced messages to socket: {e}");
+                self.reason = Some(DisconnectReason::from_error(&e));
+                return ConnStatus::Closed;
+            }
+        }
+
+        let now = Instant::now();
+        if matches!(self.config.idle_timeout, Some(t) if now - self.last_message_at >= t)
+        {
+            conn_log!(info, self, "Client idle for too long, closing client socket");
+            self.close_socket(DisconnectReason::Idle,
+                              Some(CloseFrame { code:   CloseCode::Away,
+                                                reason: "Idle Timeout".into() }));
+            return ConnStatus::Closed;
+        }
+        if matches!(self.config.keepalive_interval, Some(i) if now - self.last_ping >= i)
+        {
+            conn_log!(debug, self, "Sending keepalive ping");
+            self.last_ping = now;
+            if !self.write_broadcast(Message::Ping(Vec::new()))
+            {
+                return ConnStatus::Closed;
+            }
+        }
+
+        // While draining close as soon as the current exchange is done
+        if let Some(deadline) = self.drain_deadline
+        {
+            let in_flight = self.unanswered || self.handle.has_queued();
+            if (matches!(status, ConnStatus::Idle) && !in_flight) || Instant::now() >= deadline
+            {
+                conn_log!(info, self, "Drained, closing a client socket");
+                self.close_socket(DisconnectReason::ServerShutdown,
+                                  Some(CloseFrame { code:   CloseCode::Away,
+                                                    reason: "Server Draining".into() }));
+                return ConnStatus::Closed;
+            }
+        }
+        status
+    }
+
+    /// Reads the next frame, or fails with WouldBlock without reading if
+    /// throttled
+    fn read_message(&mut self, throttled: bool) -> tungstenite::Result<Message>
+    {
+        if throttled
+        {
+            // Reading flushes queued frames, which mustn't wait on the throttle
+            self.socket.write_pending()?;
+            return Err(std::io::Error::from(std::io::ErrorKind::WouldBlock).into());
+        }
+        self.socket.read_message()
+    }
+
+    /// Runs the outgoing hook on a broadcast, None if it shouldn't be sent
+    fn intercept(&self, msg: String) -> Option<String>
+    {
+        let on_outgoing = self.shared.on_outgoing.read().unwrap().clone();
+        match (on_outgoing, self.shared.registry.info(self.id))
+        {
+            (Some(f), Some(info)) => f(&info, msg),
+            _ => Some(msg)
+        }
+    }
+
+    /// Writes msg, leaving it queued if the socket would block
+    ///
+    /// Queued frames go out on later polls. Broadcasts written meanwhile
+    /// stay counted as pending, see `hold_if_blocked`. Only real errors are
+    /// returned.
+    fn write(&mut self, msg: Message) -> tungstenite::Result<()>
+    {
+        if matches!(msg, Message::Text(_) | Message::Binary(_))
+        {
+            self.unanswered = false;
+        }
+        let result = self.socket.write_message(msg);
+        self.written(result)
+    }
+
+    /// Tries to write out frames left queued by a blocked write
+    fn flush_blocked(&mut self) -> tungstenite::Result<()>
+    {
+        if !self.write_blocked
+        {
+            return Ok(());
+        }
+        let result = self.socket.write_pending();
+        self.written(result)
+    }
+
+    /// Tracks whether the socket has taken everything written to it,
+    /// swallowing WouldBlock
+    fn written(&mut self, result: tungstenite::Result<()>) -> tungstenite::Result<()>
+    {
+        use std::io::ErrorKind::{Interrupted, WouldBlock};
+
+        match result
+        {
+            Ok(()) =>
+            {
+                if std::mem::take(&mut self.write_blocked)
+                {
+                    conn_log!(debug, self, "Client socket writable again, queued frames written");
+                    self.ctrl.release();
+                }
+                Ok(())
+            }
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), WouldBlock | Interrupted) =>
+            {
+                if !self.write_blocked
+                {
+                    conn_log!(debug, self, "Client socket not writable, leaving frames queued");
+                    self.write_blocked = true;
+                }
+                Ok(())
+            }
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Keeps a broadcast counted as pending while it sits in the socket's
+    /// send queue, so `broadcast_queue_limit` and `max_queued_bytes` still
+    /// hold back a client that stopped reading
+    fn hold_if_blocked(&mut self, len: usize)
+    {
+        if self.write_blocked
+        {
+            self.ctrl.hold(len);
+        }
+    }
+
+    /// Holds back writes until the coalescing window ends, if coalescing
+    fn cork(&mut self)
+    {
+        if let (Some(cork), None) = (&self.cork, self.corked_at)
+        {
+            cork.store(true, Ordering::Relaxed);
+            self.corked_at = Some(Instant::now());
+        }
+    }
+
+    /// Writes out everything held back since corking
+    ///
+    /// Whatever the socket won't take straight away goes out on later
+    /// polls, like any frame tungstenite couldn't finish writing.
+    fn uncork(&mut self) -> tungstenite::Result<()>
+    {
+        if let Some(cork) = &self.cork
+        {
+            cork.store(false, Ordering::Relaxed);
+        }
+        if self.corked_at.take().is_some()
+        {
+            match self.socket.get_mut().flush()
+            {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => self.write_blocked = true,
+                r => r?
+            }
+        }
+        Ok(())
+    }
+
+    fn write_broadcast(&mut self, msg: Message) -> bool
+    {
+        if let Err(e) = self.write(msg)
+        {
+            if DisconnectReason::peer_gone(&e)
+            {
+                conn_log!(debug, self, "Client went away before broadcast was written: {e}");
+            }
+            else
+            {
+                conn_log!(error, self, "Unable to write broadcast to socket: {e}");
+            }
+            self.reason = Some(DisconnectReason::from_error(&e));
+            return false;
+        }
+        true
+    }
+
+    fn on_message(&mut self, msg: Message) -> bool
+    {
+        match msg
+        {
+            Message::Text(message) if message.is_empty() && self.config.ignore_empty_messages =>
+            {
+                conn_log!(debug, self, "Ignoring empty message");
+            }
+            Message::Text(message) =>
+            {
+                self.shared
+                    .events
+                    .emit(|| ServerEvent::Message(self.id, message.clone()));
+                self.unanswered = self.shared.deferred.load(Ordering::Relaxed);
+                let result = match self.watchdog.clone()
+                {
+                    Some(watchdog) =>
+                    {
+                        // Only kept to report a handler that overran
+                        let logged = message.clone();
+                        match watchdog.run(&self.on_message, message, &self.handle)
+                        {
+                            Some(Ok((result, replies))) =>
+                            {
+                                self.replies.borrow_mut().extend(replies);
+                                result
+                            }
+                            Some(Err(panic)) =>
+                            {
+                                self.handler_panicked(panic_message(&*panic));
+                                return false;
+                            }
+                            None =>
+                            {
+                                conn_log!(warn,
+                                          self,
+                                          "Handler still running after {:?}, closing client socket. Message: {logged:?}",
+                                          self.config.handler_timeout.unwrap_or_default());
+                                self.close_socket(DisconnectReason::HandlerTimeout,
+                                                  Some(CloseFrame { code:   CloseCode::Policy,
+                                                                    reason: "Handler Timeout".into() }));
+                                return false;
+                            }
+                        }
+                    }
+                    None =>
+                    {
+                        let q = self.replies.clone();
+                        let (on_message, handle) = (&self.on_message, &self.handle);
+                        handle.enter(|| on_message(message, Box::new(move |r| q.borrow_mut().push(r)), handle))
+                    }
+                };
+                if let Err(e) = result
+                {
+                    conn_log!(error, self, "Error on message: {}", e);
+                    let on_error = self.shared.on_error.read().unwrap().clone();
+                    if let Some(text) = on_error.and_then(|f| f(&e))
+                    {
+                        if let Err(e) = self.write(Message::Text(text))
+                        {
+                            conn_log!(error, self, "Unable to write error message to client: {e}");
+                        }
+                    }
+                    self.close_socket(DisconnectReason::HandlerError,
+                                      Some(CloseFrame { code:   CloseCode::Error,
+                                                        reason: e.to_string().into() }));
+                    return false;
+                }
+                // Reuse the buffer across messages, replies are only pushed
+                // while the handler runs so the borrow can't overlap
+                let replies = self.replies.clone();
+                let mut replies = replies.borrow_mut();
+                for reply in replies.drain(..)
+                {
+                    let msg = match reply
+                    {
+                        Reply::Text(t) => Message::Text(t),
+                        Reply::Binary(b) => Message::Binary(b),
+                        Reply::Close(cf) =>
+                        {
+                            self.close_socket(DisconnectReason::ServerClose, Some(cf));
+                            return false;
+                        }
+                    };
+                    if let Err(e) = self.write(msg)
+                    {
+                        if DisconnectReason::peer_gone(&e)
+                        {
+                            conn_log!(debug, self, "Client went away before reply was written: {e}");
+                        }
+                        else
+                        {
+                            conn_log!(error, self, "Error writing message back to client: {e}");
+                        }
+                        self.close_socket(DisconnectReason::from_error(&e),
+                                          Some(CloseFrame { code:   CloseCode::Error,
+                                                            reason: e.to_string().into() }));
+                        return false;
+                    }
+                }
+            }
+            Message::Binary(_) =>
+            {
+                conn_log!(warn, self, "Binary data not supported, closing client socket");
+                self.close_socket(DisconnectReason::ProtocolError,
+                                  Some(CloseFrame { code:   CloseCode::Unsupported,
+                                                    reason: "Binary Not Supported".into() }));
+                return false;
+            }
+            Message::Ping(_) =>
+            {
+                conn_log!(debug, self, "Receiving Ping.")
+            }
+            Message::Pong(payload) =>
+            {
+                conn_log!(debug, self, "Receiving Pong.");
+                self.shared.acks.ack(&payload);
+            }
+            Message::Close(c) =>
+            {
+                self.close_socket(DisconnectReason::ClientClose, c);
+                return false;
+            }
+            Message::Frame(_) =>
+            {
+                unreachable!()
+            }
+        }
+        true
+    }
+
+    fn close_socket(&mut self, reason: DisconnectReason, cf: Option<CloseFrame>)
+    {
+        self.reason = Some(reason);
+        // Held back messages go out ahead of the close frame
+        if let Some(cork) = self.cork.as_ref()
+        {
+            cork.store(false, Ordering::Relaxed);
+        }
+        let _ = self.socket.close(cf);
+        // The socket is non-blocking, keep flushing until the close frame
+        // is written out or the peer goes away. Bounded as this holds up
+        // the worker, a client that isn't reading never takes the frame
+        let timeout = Instant::now() + CLOSE_FLUSH_TIMEOUT;
+        while Instant::now() < timeout
+        {
+            match self.socket.write_pending()
+            {
+                Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    std::thread::sleep(Duration::from_millis(1))
+                }
+                _ => break
+            }
+        }
+    }
+}
+
+impl Drop for Conn
+{
+    fn drop(&mut self)
+    {
+        let reason = self.reason.unwrap_or(DisconnectReason::IoError);
+        conn_log!(info, self, "Connection closed: {reason:?}");
+        let on_disconnect = self.shared.on_disconnect.read().unwrap().clone();
+        if let (Some(on_disconnect), Some(info)) = (on_disconnect, self.shared.registry.info(self.id))
+        {
+            if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| on_disconnect(&info, reason)))
+            {
+                conn_log!(error, self, "Disconnect hook panicked: {}", panic_message(&*panic));
+            }
+        }
+        self.shared.events.emit(|| ServerEvent::Disconnected(self.id, reason));
+        self.shared.registry.remove(self.id);
+    }
+}
@@ -0,0 +1,61 @@
+use super::*;
+use std::net::SocketAddr;
+
+/// Something that happened on the server, see `SimpleSockleServer::events`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerEvent
+{
+    /// A client completed the handshake
+    Connected(u64, SocketAddr),
+    /// A text message arrived, delivered alongside the message handler
+    Message(u64, String),
+    /// A connection ended
+    Disconnected(u64, DisconnectReason)
+}
+
+/// Channels subscribed through `events`
+#[derive(Default)]
+pub(crate) struct Events
+{
+    subscribers: Mutex<Vec<Sender<ServerEvent>>>
+}
+
+impl Events
+{
+    fn subscribe(&self) -> Receiver<ServerEvent>
+    {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Sends the event made by f to every subscriber, dropping those whose
+    /// receiver is gone
+    ///
+    /// f is only called when there is a subscriber, so events that copy
+    /// cost nothing otherwise.
+    pub(crate) fn emit<F: FnOnce() -> ServerEvent>(&self, f: F)
+    {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if subscribers.is_empty()
+        {
+            return;
+        }
+        let event = f();
+        subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+impl SimpleSockleServer
+{
+    /// Channel receiving every connect, text message and disconnect
+    ///
+    /// Lets all events be handled on one thread of the caller's choosing.
+    /// Messages still go to the handler passed to listen as well. Each call
+    /// returns a new receiver getting events from then on, dropping it
+    /// unsubscribes. Events queue without bound while not received.
+    pub fn events(&self) -> Receiver<ServerEvent>
+    {
+        self.shared.events.subscribe()
+    }
+}
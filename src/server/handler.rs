@@ -0,0 +1,54 @@
+use super::*;
+
+/// Handles all server events in one place, see
+/// `SimpleSockleServer::listen_with_handler`
+///
+/// Every method has a default doing nothing, implement the ones needed.
+/// Methods are called on the worker threads, a single handler is shared by
+/// all of them.
+pub trait SockleHandler: Send + Sync + 'static
+{
+    /// Same as `SimpleSockleServer::on_connect`
+    fn on_connect(&self, _info: &ConnectionInfo, _handle: ConnectionHandle)
+    {
+    }
+
+    /// Called for each text message, same as the handler passed to
+    /// `listen_with_reply`
+    ///
+    /// `ConnectionHandle::current` is the client that sent it.
+    fn on_message(&self, _message: String, _reply: Box<dyn Fn(Reply)>) -> Result<()>
+    {
+        Ok(())
+    }
+
+    /// Same as `SimpleSockleServer::on_disconnect`
+    fn on_disconnect(&self, _info: &ConnectionInfo, _reason: DisconnectReason)
+    {
+    }
+
+    /// Same as `SimpleSockleServer::on_handler_error`
+    fn on_error(&self, _error: &anyhow::Error) -> Option<String>
+    {
+        None
+    }
+}
+
+impl SimpleSockleServer
+{
+    /// Same as `listen_with_reply`, but with every event going to handler
+    ///
+    /// Replaces any `on_connect`, `on_disconnect` and `on_handler_error`
+    /// hooks set before.
+    pub fn listen_with_handler<H: SockleHandler>(&mut self, listen_address: &str, handler: H) -> Result<()>
+    {
+        let handler = Arc::new(handler);
+        let h = handler.clone();
+        self.on_connect(move |info, handle| h.on_connect(info, handle));
+        let h = handler.clone();
+        self.on_disconnect(move |info, reason| h.on_disconnect(info, reason));
+        let h = handler.clone();
+        self.on_handler_error(move |e| h.on_error(e));
+        self.listen_with_reply(listen_address, move |message, reply| handler.on_message(message, reply))
+    }
+}
@@ -0,0 +1,163 @@
+use super::*;
+use coalesce::CoalescingStream;
+use std::{cell::RefCell, net::SocketAddr, rc::Rc};
+use tungstenite::{handshake::{server::{Callback, ErrorResponse, ServerHandshake},
+                              HandshakeError,
+                              MidHandshake},
+                  WebSocket};
+
+type Transport = Box<dyn SockleTransport>;
+
+/// Checks the upgrade request against the config and `on_handshake`,
+/// noting the subprotocol agreed
+struct HandshakeCallback
+{
+    config:       Arc<SockleServerConfig>,
+    on_handshake: Option<OnHandshakeFn>,
+    subprotocol:  Rc<RefCell<Option<String>>>
+}
+
+impl Callback for HandshakeCallback
+{
+    fn on_request(self, request: &Request, mut response: Response) -> Result<Response, ErrorResponse>
+    {
+        self.config.check_origin(request)?;
+        self.config.negotiate_subprotocol(request, &mut response);
+        if let Some(f) = self.on_handshake
+        {
+            f(request, &mut response);
+        }
+        *self.subprotocol.borrow_mut() = response.headers()
+                                                 .get("Sec-WebSocket-Protocol")
+                                                 .and_then(|v| v.to_str().ok())
+                                                 .map(str::to_string);
+        Ok(response)
+    }
+}
+
+/// A connection that completed the websocket upgrade, ready for
+/// `Conn::accept`
+pub(crate) struct Upgraded
+{
+    pub(crate) socket:      WebSocket<Transport>,
+    pub(crate) peer_addr:   SocketAddr,
+    pub(crate) local_addr:  SocketAddr,
+    pub(crate) subprotocol: Option<String>,
+    pub(crate) cork:        Option<Arc<AtomicBool>>
+}
+
+/// Result of advancing a handshake once
+pub(crate) enum HandshakeStatus
+{
+    /// Still waiting on the client
+    Pending(Handshake),
+    /// Upgrade complete
+    Done(Upgraded),
+    /// Failed, timed out or the server is stopping, the stream is dropped
+    Failed
+}
+
+/// A websocket upgrade in progress, driven without blocking by the worker
+/// that owns it
+pub(crate) struct Handshake
+{
+    mid:         MidHandshake<ServerHandshake<Transport, HandshakeCallback>>,
+    peer_addr:   SocketAddr,
+    local_addr:  SocketAddr,
+    subprotocol: Rc<RefCell<Option<String>>>,
+    cork:        Option<Arc<AtomicBool>>,
+    deadline:    Option<Instant>
+}
+
+impl Handshake
+{
+    /// Starts the upgrade on a newly accepted stream, finishing it straight
+    /// away if the request has already arrived
+    pub(crate) fn start(stream: Transport, shared: &Shared, config: Arc<SockleServerConfig>) -> HandshakeStatus
+    {
+        let (peer_addr, local_addr) = match stream.peer_addr()
+                                                  .and_then(|p| Ok((p, stream.local_addr()?)))
+        {
+            Ok(a) => a,
+            Err(e) =>
+            {
+                log::error!("Unable to get addresses of incoming socket: {e}");
+                return HandshakeStatus::Failed;
+            }
+        };
+        let (stream, cork) = match config.coalesce_window
+        {
+            Some(_) =>
+            {
+                let (stream, cork) = CoalescingStream::new(stream);
+                (Box::new(stream) as Transport, Some(cork))
+            }
+            None => (stream, None)
+        };
+        if let Err(e) = stream.set_read_timeout(None)
+                              .and_then(|_| stream.set_write_timeout(None))
+                              .and_then(|_| stream.set_nonblocking(true))
+        {
+            log::error!("Unable to set incoming stream from {peer_addr} non-blocking: {e}");
+            return HandshakeStatus::Failed;
+        }
+        let subprotocol = Rc::new(RefCell::new(None));
+        let callback = HandshakeCallback { config:       config.clone(),
+                                           on_handshake: shared.on_handshake.read().unwrap().clone(),
+                                           subprotocol:  subprotocol.clone() };
+        let deadline = config.handshake_timeout.map(|t| Instant::now() + t);
+        let result = tungstenite::accept_hdr_with_config(stream, callback, Some(config.websocket_config()));
+        Handshake::finish(result, peer_addr, local_addr, subprotocol, cork, deadline)
+    }
+
+    /// Continues the upgrade with whatever the client has sent since
+    pub(crate) fn advance(self, shared: &Shared) -> HandshakeStatus
+    {
+        if shared.stopping.load(Ordering::SeqCst)
+        {
+            log::debug!("Shutting down, dropping incoming stream from {} mid handshake", self.peer_addr);
+            return HandshakeStatus::Failed;
+        }
+        if matches!(self.deadline, Some(d) if Instant::now() >= d)
+        {
+            log::warn!("Handshake with {} timed out, dropping incoming stream", self.peer_addr);
+            return HandshakeStatus::Failed;
+        }
+        let Handshake { mid,
+                        peer_addr,
+                        local_addr,
+                        subprotocol,
+                        cork,
+                        deadline } = self;
+        Handshake::finish(mid.handshake(), peer_addr, local_addr, subprotocol, cork, deadline)
+    }
+
+    fn finish(result: Result<WebSocket<Transport>, HandshakeError<ServerHandshake<Transport, HandshakeCallback>>>,
+              peer_addr: SocketAddr,
+              local_addr: SocketAddr,
+              subprotocol: Rc<RefCell<Option<String>>>,
+              cork: Option<Arc<AtomicBool>>,
+              deadline: Option<Instant>)
+              -> HandshakeStatus
+    {
+        match result
+        {
+            Ok(socket) => HandshakeStatus::Done(Upgraded { socket,
+                                                           peer_addr,
+                                                           local_addr,
+                                                           subprotocol: subprotocol.take(),
+                                                           cork }),
+            Err(HandshakeError::Interrupted(mid)) => HandshakeStatus::Pending(Handshake { mid,
+                                                                                          peer_addr,
+                                                                                          local_addr,
+                                                                                          subprotocol,
+                                                                                          cork,
+                                                                                          deadline }),
+            Err(HandshakeError::Failure(e)) =>
+            {
+                log::error!("Error accepting incoming stream from {peer_addr}: {e}");
+                HandshakeStatus::Failed
+            }
+        }
+    }
+}
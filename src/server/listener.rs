@@ -0,0 +1,145 @@
+use super::*;
+use std::{net::TcpStream,
+          sync::mpsc::{Receiver, SyncSender}};
+use tungstenite::{handshake::server::ErrorResponse,
+                  http::{HeaderValue, StatusCode}};
+
+/// Longest the listen thread spends turning away a client while overloaded
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Seconds overloaded clients are asked to wait before retrying
+const RETRY_AFTER_SECS: u64 = 1;
+
+/// Overloaded clients waiting to be turned away, beyond this they are
+/// dropped without a response
+const REJECT_BACKLOG: usize = 64;
+
+/// Accept loop handing incoming streams to the worker pool
+pub(crate) struct Listener
+{
+    server: TcpListener,
+    pool:   Arc<WorkerPool>,
+    shared: Arc<Shared>,
+    ctrl:   Receiver<()>,
+    config: Arc<SockleServerConfig>
+}
+
+impl Listener
+{
+    pub(crate) fn new(server: TcpListener,
+                      pool: Arc<WorkerPool>,
+                      shared: Arc<Shared>,
+                      ctrl: Receiver<()>,
+                      config: Arc<SockleServerConfig>)
+                      -> Self
+    {
+        Self { server,
+               pool,
+               shared,
+               ctrl,
+               config }
+    }
+
+    /// Whether the connection limit is reached or the outbound byte budget
+    /// used up
+    fn overloaded(&self) -> bool
+    {
+        matches!(self.config.max_connections, Some(max) if self.pool.load() >= max)
+        || matches!(self.config.max_queued_bytes, Some(max) if self.shared.registry.queued_bytes() >= max)
+    }
+
+    /// Starts the thread answering overloaded clients with 503, ends once
+    /// the returned sender is dropped
+    ///
+    /// Kept off the listen thread so clients stalling their rejection don't
+    /// hold up accepts, and off the workers so overload doesn't reach them.
+    fn spawn_rejector(&self) -> Option<SyncSender<TcpStream>>
+    {
+        let (rejects, rejects_r) = std::sync::mpsc::sync_channel(REJECT_BACKLOG);
+        match self.config
+                  .thread_builder("Rejector")
+                  .spawn(move || rejects_r.into_iter().for_each(Listener::reject_busy))
+        {
+            Ok(_) => Some(rejects),
+            Err(e) =>
+            {
+                log::error!("Unable to start rejector thread, overloaded clients will be dropped: {e}");
+                None
+            }
+        }
+    }
+
+    /// Answers the upgrade request with 503 and a Retry-After header,
+    /// bounded by `REJECT_TIMEOUT` against clients that stall
+    fn reject_busy(stream: TcpStream)
+    {
+        let timeout = Some(REJECT_TIMEOUT);
+        if let Err(e) = stream.set_nonblocking(false)
+                              .and_then(|_| stream.set_read_timeout(timeout))
+                              .and_then(|_| stream.set_write_timeout(timeout))
+        {
+            log::error!("Unable to prepare incoming stream for rejection: {e}");
+            return;
+        }
+        let callback = |_: &Request, _: Response| {
+            let mut response = ErrorResponse::new(Some("Server busy".to_string()));
+            *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            response.headers_mut()
+                    .insert("Retry-After", HeaderValue::from(RETRY_AFTER_SECS));
+            Err(response)
+        };
+        if let Err(e) = tungstenite::accept_hdr(stream, callback)
+        {
+            log::debug!("Rejected incoming stream: {e}");
+        }
+    }
+
+    /// Hands an accepted stream to the pool, or to the rejector if
+    /// overloaded
+    fn incoming(&self, stream: TcpStream, rejects: Option<&SyncSender<TcpStream>>)
+    {
+        if self.overloaded()
+        {
+            log::warn!("Server overloaded, rejecting incoming stream with 503");
+            if rejects.is_none_or(|r| r.try_send(stream).is_err())
+            {
+                log::warn!("Too many clients waiting on a 503, dropping incoming stream");
+            }
+            return;
+        }
+        if let Err(e) = self.config.apply(&stream)
+        {
+            log::error!("Unable to set socket options on incoming stream: {e}");
+            return;
+        }
+        self.pool.dispatch(Box::new(stream))
+    }
+
+    /// Accepts connections until signalled through ctrl or ctrl is dropped
+    pub(crate) fn run(self)
+    {
+        let rejects = self.spawn_rejector();
+        for stream in self.server.incoming()
+        {
+            match stream
+            {
+                Ok(s) => self.incoming(s, rejects.as_ref()),
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    std::thread::sleep(self.config.poll_interval);
+                }
+                Err(e) =>
+                {
+                    log::error!("Error opening incoming stream: {e}");
+                }
+            }
+
+            if matches!(self.ctrl.try_recv(), Err(TryRecvError::Disconnected) | Ok(_))
+            {
+                log::debug!("Server shutdown requested, ending listen thread");
+                break;
+            }
+        }
+        log::info!("Sockle server has shutdown");
+    }
+}
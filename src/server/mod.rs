@@ -1,303 +1,808 @@
 use anyhow::Result;
-use std::{collections::VecDeque,
-          net::{TcpListener, TcpStream},
-          sync::{mpsc::TryRecvError, Arc},
+use std::{net::TcpListener,
+          sync::{atomic::{AtomicBool, Ordering},
+                 mpsc::{Receiver, Sender, TryRecvError},
+                 Arc,
+                 Mutex,
+                 RwLock,
+                 Weak},
+          thread::JoinHandle,
           time::{Duration, Instant}};
-use tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame},
+use crate::{MemoryStream, SimpleSockleError, SockleClose, SockleTransport};
+use tungstenite::{handshake::server::{Request, Response},
+                  protocol::{frame::coding::CloseCode, CloseFrame},
                   Message};
 
+mod acks;
+mod coalesce;
+mod codec;
+mod config;
+mod conn;
+mod events;
+mod handler;
+mod handshake;
+mod listener;
+mod pool;
+mod registry;
+mod throttle;
+mod watchdog;
+
+pub use config::SockleServerConfig;
+pub use conn::DisconnectReason;
+pub use events::ServerEvent;
+use events::Events;
+pub use handler::SockleHandler;
+pub use registry::{ConnectionHandle, ConnectionInfo};
+use acks::Acks;
+use listener::Listener;
+use pool::WorkerPool;
+use registry::Registry;
+use throttle::Throttle;
+
 pub trait SockleServer
 {
     /// Spawns a thread and listens on given ip/port
+    ///
+    /// Connections are served by a fixed pool of worker threads, each
+    /// multiplexing many clients. A slow `on_message` delays the other
+    /// clients sharing its worker.
     fn listen<F: Fn(String, Box<dyn Fn(String)>) -> Result<()> + Send + Sync + 'static>(
         &mut self,
         listen_address: &str,
         on_message: F)
+        -> Result<()>
+    {
+        self.listen_with_reply(listen_address, move |message, reply| {
+                on_message(message, Box::new(move |s| reply(Reply::Text(s))))
+            })
+    }
+
+    /// Same as listen_with_reply, but the handler borrows the message
+    ///
+    /// Saves handlers that only inspect the message from taking ownership
+    /// of it. tungstenite still decodes each message into its own `String`,
+    /// which is dropped once the handler returns.
+    fn listen_str<F: Fn(&str, Box<dyn Fn(Reply)>) -> Result<()> + Send + Sync + 'static>(&mut self,
+                                                                                        listen_address: &str,
+                                                                                        on_message: F)
+                                                                                        -> Result<()>
+    {
+        self.listen_with_reply(listen_address, move |message, reply| on_message(&message, reply))
+    }
+
+    /// Same as listen, but the handler replies with a `Reply`
+    ///
+    /// Allows answering with binary data or closing the connection.
+    fn listen_with_reply<F: Fn(String, Box<dyn Fn(Reply)>) -> Result<()> + Send + Sync + 'static>(
+        &mut self,
+        listen_address: &str,
+        on_message: F)
+        -> Result<()>;
+
+    /// Same as listen, but accepts on a listener that is already bound
+    ///
+    /// For socket activation or listeners set up by the caller. The
+    /// listener is switched to non-blocking.
+    fn listen_from<F: Fn(String, Box<dyn Fn(String)>) -> Result<()> + Send + Sync + 'static>(&mut self,
+                                                                                           listener: TcpListener,
+                                                                                           on_message: F)
+                                                                                           -> Result<()>
+    {
+        self.listen_from_with_reply(listener, move |message, reply| {
+                on_message(message, Box::new(move |s| reply(Reply::Text(s))))
+            })
+    }
+
+    /// Same as listen_from, but the handler replies with a `Reply`
+    fn listen_from_with_reply<F: Fn(String, Box<dyn Fn(Reply)>) -> Result<()> + Send + Sync + 'static>(
+        &mut self,
+        listener: TcpListener,
+        on_message: F)
         -> Result<()>;
 
     /// Sends a message to all connected clients
+    ///
+    /// The payload is shared by all recipients, only the frame header is
+    /// encoded per connection. Messages are never compressed, so there is
+    /// no per connection deflate work to share.
+    ///
+    /// Only queues the message, so it is safe to call from a handler
+    /// through a `SockleServerHandle`. The client that sent the message
+    /// being handled gets it too, once the handler returns, see
+    /// `broadcast_others` to leave it out.
     fn send(&self, msg: String);
 
+    /// Sends a message to all connected clients except the one with id
+    /// except, returning the number it was queued for
+    ///
+    /// For relaying a client's message to everyone else from its handler
+    /// without echoing it back. Handlers that aren't passed the sender's
+    /// `ConnectionHandle` get its id from `ConnectionHandle::current`.
+    fn broadcast_others(&self, except: u64, msg: String) -> usize;
+
+    /// Sends a message to all connected clients in the given tier
+    ///
+    /// Each connection writes high priority messages before any normal ones
+    /// it still has queued, so they overtake a backlog of updates to a slow
+    /// client. Within a tier messages keep the order they were sent in.
+    /// Replies, binary data and shutdown aren't prioritised, they are
+    /// queued as normal. Both tiers count towards the queue limits.
+    fn send_with_priority(&self, msg: String, priority: Priority);
+
+    /// Sends binary data to all connected clients
+    fn send_binary(&self, data: Vec<u8>);
+
+    /// Sends a message to the clients whose connection matches pred
+    ///
+    /// Returns the number of clients it was queued for. pred is called with
+    /// the registry locked, so it must not call back into the server.
+    fn send_where<P: Fn(&ConnectionInfo) -> bool>(&self, pred: P, msg: String) -> usize;
+
+    /// Sends a message to all connected clients and waits until expected
+    /// of them acknowledge it or timeout elapses, returning how many did
+    ///
+    /// The message is followed by a ping carrying a correlation id, the pong
+    /// clients answer it with is the acknowledgement. Clients reply to pings
+    /// as they read, so an ack means the client has read past the message,
+    /// no client side changes are needed. Blocks the calling thread, don't
+    /// call it from a handler.
+    fn broadcast_await_acks(&self, msg: String, expected: usize, timeout: Duration) -> usize;
+
     /// Closes all connections and stops listening
     ///
-    /// Blocks until thread has ended
+    /// Blocks until the listen thread and every worker thread have ended, so
+    /// nothing is still writing to clients once it returns. Returns Ok(())
+    /// without doing anything if the server was never started or is already
+    /// shut down.
     fn shutdown(&self) -> Result<()>;
 
+    /// Stops accepting new connections, letting existing ones finish
+    ///
+    /// Each connection is closed once it has no exchange in flight, or
+    /// forcibly once the grace period elapses. An exchange is in flight
+    /// while sends to the connection are still queued and, with
+    /// `listen_deferred`, from a message until something is sent back
+    /// through its `ConnectionHandle`. Other handlers are done with a
+    /// message once they return.
+    /// Blocks until the listen thread has ended, not for connections to close
+    fn drain(&self, grace: Duration) -> Result<()>;
+
     /// Number of client connections
+    ///
+    /// Connections count once the server has finished their handshake,
+    /// which can be a moment after `connect` returns on the client.
     fn connection_count(&self) -> usize;
-}
 
-pub enum SockleServerMessage
-{
-    Send(String),
-    Shutdown
+    /// Blocks until at least count clients are connected, returning false
+    /// if timeout elapses first
+    fn wait_for_connections(&self, count: usize, timeout: Duration) -> bool;
+
+    /// Current server wide counters
+    fn stats(&self) -> SockleServerStats;
+
+    /// Number of client connections over IPv4 and over IPv6, see
+    /// `ConnectionInfo::is_ipv6`
+    fn connection_count_by_family(&self) -> (usize, usize);
+
+    /// Details of each client connection, ordered by id
+    fn connections(&self) -> Vec<ConnectionInfo>;
+
+    /// Details of a single connection, None if it has closed
+    fn connection(&self, id: u64) -> Option<ConnectionInfo>;
+
+    /// Adds connection id to room, returning false if it has closed
+    ///
+    /// Rooms are just names, they exist while they have members. A
+    /// connection can be in any number of them and leaves them all when it
+    /// disconnects. Handlers can use `ConnectionHandle::join_room` instead.
+    fn join_room(&self, id: u64, room: &str) -> bool;
+
+    /// Removes connection id from room, returning whether it was in it
+    fn leave_room(&self, id: u64, room: &str) -> bool;
+
+    /// Sends a message to every connection in room, returning the number
+    /// it was queued for
+    ///
+    /// Subject to the same queue limits as `send`.
+    fn send_to_room(&self, room: &str, msg: String) -> usize;
+
+    /// Ids of the connections in room, in ascending order
+    fn room_members(&self, room: &str) -> Vec<u64>;
 }
 
-pub struct SimpleSockleServer
+/// Reply to the client that sent the message being handled
+pub enum Reply
 {
-    thread_ctrl:    Option<std::sync::mpsc::Sender<()>>,
-    thread_senders: Arc<std::sync::Mutex<Vec<std::sync::mpsc::Sender<SockleServerMessage>>>>
+    Text(String),
+    Binary(Vec<u8>),
+    /// Closes the connection, replies queued after this are dropped
+    Close(CloseFrame<'static>)
 }
 
-impl Default for SimpleSockleServer
+impl From<SockleClose> for Reply
 {
-    fn default() -> Self
+    fn from(close: SockleClose) -> Self
     {
-        Self::new()
+        Reply::Close(close.into())
     }
 }
 
-impl SimpleSockleServer
+/// Tier a message is queued in for each connection, see
+/// `SockleServer::send_with_priority`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority
 {
-    pub fn new() -> Self
+    /// Written before any normal messages still queued
+    High,
+    #[default]
+    Normal
+}
+
+/// Ctrl message sent to each connection through the registry
+///
+/// Broadcast payloads are shared between all recipients, each connection
+/// only copies it when encoding its own frame.
+#[derive(Clone)]
+pub enum SockleServerMessage
+{
+    Send(Arc<str>, Priority),
+    SendBinary(Arc<[u8]>),
+    /// Send followed by a ping carrying the ack correlation id
+    SendWithAck(Arc<str>, u64),
+    /// Deferred `Reply::Close`
+    Close(CloseFrame<'static>),
+    Drain(Instant),
+    Shutdown
+}
+
+impl SockleServerMessage
+{
+    /// Tier the message is queued in, only sends can be high
+    pub(crate) fn priority(&self) -> Priority
     {
-        SimpleSockleServer { thread_ctrl:    None,
-                             thread_senders: Default::default() }
+        match self
+        {
+            SockleServerMessage::Send(_, priority) => *priority,
+            _ => Priority::Normal
+        }
+    }
+
+    /// Payload size of data messages, None for control messages
+    pub(crate) fn data_len(&self) -> Option<usize>
+    {
+        match self
+        {
+            SockleServerMessage::Send(msg, _) | SockleServerMessage::SendWithAck(msg, _) => Some(msg.len()),
+            SockleServerMessage::SendBinary(data) => Some(data.len()),
+            SockleServerMessage::Close(_) | SockleServerMessage::Drain(_) | SockleServerMessage::Shutdown => None
+        }
     }
 }
 
-pub type OnMessageFn = Arc<dyn Fn(String, Box<dyn Fn(String)>) -> Result<()> + Send + Sync>;
+/// Snapshot of server wide counters, see `SockleServer::stats`
+#[derive(Clone, Debug)]
+pub struct SockleServerStats
+{
+    pub connections:      usize,
+    /// Bytes of broadcasts and handle sends queued for connections but not
+    /// yet written, counted once per recipient
+    pub queued_bytes:     usize,
+    /// `SockleServerConfig::max_message_rate`
+    pub max_message_rate: Option<u32>,
+    /// Share of the message rate budget currently used up, from 0 to 1,
+    /// messages are being held back at 1. Always 0 without a limit
+    pub rate_utilization: f64,
+    /// How long the live connections have been connected, None without
+    /// any
+    pub uptime:           Option<UptimeStats>
+}
+
+/// Spread of connection uptimes, see `SockleServerStats::uptime`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UptimeStats
+{
+    pub min:  Duration,
+    pub max:  Duration,
+    pub mean: Duration
+}
+
+/// How long dropping a server waits for its listen thread to end
+const DROP_JOIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct SimpleSockleServer
+{
+    shared:        Arc<Shared>,
+    config:        Arc<SockleServerConfig>,
+    listen_thread: Option<JoinHandle<()>>
+}
 
-pub struct Conn
+/// Cloneable handle for shutting a server down from another thread
+///
+/// Needed with `listen_blocking`, which holds on to the server until it
+/// returns. Can be moved into a Ctrl-C handler, or into a message handler
+/// to broadcast from it.
+#[derive(Clone)]
+pub struct SockleServerHandle
 {
-    socket:     tungstenite::WebSocket<TcpStream>,
-    ctrl:       std::sync::mpsc::Receiver<SockleServerMessage>,
-    on_message: OnMessageFn
+    shared: Arc<Shared>,
+    config: Arc<SockleServerConfig>
 }
 
-impl Conn
+impl SockleServerHandle
 {
-    fn new(socket: tungstenite::WebSocket<TcpStream>,
-           ctrl: std::sync::mpsc::Receiver<SockleServerMessage>,
-           on_message: OnMessageFn)
-           -> Conn
+    /// Same as `SockleServer::send`, returning the number of clients it
+    /// was queued for
+    pub fn send(&self, msg: String) -> usize
     {
-        Self { socket,
-               ctrl,
-               on_message }
+        self.shared
+            .registry
+            .send_where(|_| true, SockleServerMessage::Send(msg.into(), Priority::Normal), &self.config)
     }
 
-    fn on_accept(mut self)
+    /// Same as `SockleServer::broadcast_others`
+    pub fn broadcast_others(&self, except: u64, msg: String) -> usize
     {
-        if let Err(e) = self.socket
-                            .get_ref()
-                            .set_read_timeout(Some(Duration::from_millis(15)))
-        {
-            log::error!("Unable to set timeout on incoming socket: {e}");
-            return;
-        }
+        self.shared
+            .registry
+            .send_where(|c| c.id != except, SockleServerMessage::Send(msg.into(), Priority::Normal), &self.config)
+    }
+
+    /// Same as `SockleServer::shutdown`
+    pub fn shutdown(&self) -> Result<()>
+    {
+        self.shared.shutdown()
+    }
+}
+
+/// State shared by the server, its handles and its threads
+#[derive(Default)]
+pub(crate) struct Shared
+{
+    thread_ctrl:                Mutex<Option<Sender<()>>>,
+    pub(crate) registry:        Registry,
+    /// Worker threads of the current pool, joined on shutdown
+    pub(crate) workers:         Mutex<Vec<JoinHandle<()>>>,
+    /// Pool of the running listener, for connections served with
+    /// `serve_stream`
+    pool:                       Mutex<Weak<WorkerPool>>,
+    pub(crate) on_connect:      RwLock<Option<OnConnectFn>>,
+    pub(crate) on_disconnect:   RwLock<Option<OnDisconnectFn>>,
+    pub(crate) on_error:        RwLock<Option<OnErrorFn>>,
+    pub(crate) on_decode_error: RwLock<Option<OnDecodeErrorFn>>,
+    pub(crate) on_outgoing:     RwLock<Option<OnOutgoingFn>>,
+    pub(crate) on_handshake:    RwLock<Option<OnHandshakeFn>>,
+    pub(crate) acks:            Acks,
+    pub(crate) events:          Events,
+    pub(crate) throttle:        Throttle,
+    /// Set before the shutdown broadcast so connections registering after
+    /// it close themselves instead of missing it
+    pub(crate) stopping:        AtomicBool,
+    /// Deadline of a drain in progress, set before its broadcast so
+    /// connections registering after it drain too
+    pub(crate) drain_deadline:  Mutex<Option<Instant>>,
+    /// The handler may answer after returning, see `listen_deferred`
+    pub(crate) deferred:        AtomicBool
+}
 
-        loop
+impl Shared
+{
+    /// Signals the listen thread to end and waits for it
+    ///
+    /// No-op if the server was never started or has already stopped.
+    fn stop_listening(&self) -> Result<()>
+    {
+        let tc = match self.thread_ctrl.lock().unwrap().take()
         {
-            match self.socket.read_message()
+            Some(tc) => tc,
+            None =>
             {
-                Ok(msg) =>
-                {
-                    if !self.on_message(msg)
-                    {
-                        return;
-                    }
-                }
-                Err(tungstenite::error::Error::Io(e))
-                    if matches!(e.kind(),
-                                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
-                {}
-                Err(e) =>
-                {
-                    log::error!("Error on client socket: {e}");
-                    self.close_socket(Some(CloseFrame { code:   CloseCode::Error,
-                                                        reason: e.to_string().into() }));
-                    return;
-                }
-            }
-            match self.ctrl.try_recv()
-            {
-                Ok(SockleServerMessage::Send(msg)) =>
-                {
-                    log::debug!("Received Send ctrl message on socket, writing to client");
-                    if let Err(e) = self.socket.write_message(Message::Text(msg))
-                    {
-                        log::error!("Unable to write broadcast to socket: {e}");
-                        return;
-                    }
-                }
-                Ok(SockleServerMessage::Shutdown) =>
-                {
-                    log::info!("Shutting down, closing a client socket");
-                    self.close_socket(Some(CloseFrame { code:   CloseCode::Normal,
-                                                        reason: "Server Shutdown".into() }));
-                    return;
-                }
-                Err(TryRecvError::Disconnected) =>
-                {
-                    log::warn!("Client ctrl channel disconnected, closing client socket");
-                    self.close_socket(Some(CloseFrame { code:   CloseCode::Normal,
-                                                        reason: "Server Error".into() }));
-                    return;
-                }
-                Err(TryRecvError::Empty) => std::thread::yield_now()
+                log::debug!("Server not listening, nothing to stop");
+                return Ok(());
             }
+        };
+        if let Err(e) = tc.send(())
+        {
+            let err = format!("Unable to signal listen thread to end: {e}");
+            log::error!("{err}");
+            anyhow::bail!(err);
         }
+        while tc.send(()).is_ok()
+        {
+            std::thread::yield_now();
+        }
+        Ok(())
     }
 
-    fn on_message(&mut self, msg: Message) -> bool
+    fn shutdown(&self) -> Result<()>
     {
-        match msg
+        self.stopping.store(true, Ordering::SeqCst);
+        self.registry.broadcast(|| SockleServerMessage::Shutdown);
+        self.stop_listening()?;
+        self.join_workers();
+        Ok(())
+    }
+
+    /// Waits for the worker threads to close their connections and end
+    ///
+    /// Skips the calling thread, so shutting down from a handler doesn't
+    /// deadlock.
+    fn join_workers(&self)
+    {
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers
         {
-            Message::Text(message) =>
-            {
-                let q = Arc::new(std::sync::Mutex::new(VecDeque::new()));
-                let q2 = q.clone();
-                if let Err(e) =
-                    (self.on_message)(message, Box::new(move |s| q2.lock().unwrap().push_back(s)))
-                {
-                    log::error!("Error on message: {}", e);
-                    self.close_socket(Some(CloseFrame { code:   CloseCode::Error,
-                                                        reason: e.to_string().into() }));
-                    return false;
-                }
-                while let Some(msg) = q.lock().unwrap().pop_front()
-                {
-                    if let Err(e) = self.socket.write_message(Message::Text(msg))
-                    {
-                        log::error!("Error writing message back to client: {e}");
-                        self.close_socket(Some(CloseFrame { code:   CloseCode::Error,
-                                                            reason: e.to_string().into() }));
-                        return false;
-                    }
-                }
-            }
-            Message::Binary(_) =>
-            {
-                unimplemented!("Binary data not supported")
-            }
-            Message::Ping(_) =>
-            {
-                log::debug!("Receiving Ping.")
-            }
-            Message::Pong(_) =>
+            if worker.thread().id() == std::thread::current().id()
             {
-                log::debug!("Receiving Pong.")
+                continue;
             }
-            Message::Close(c) =>
+            if worker.join().is_err()
             {
-                self.close_socket(c);
-                return false;
-            }
-            Message::Frame(_) =>
-            {
-                unreachable!()
+                log::error!("Sockle server worker panicked");
             }
         }
-        true
     }
+}
 
-    fn close_socket(&mut self, cf: Option<CloseFrame>)
+impl Default for SimpleSockleServer
+{
+    fn default() -> Self
     {
-        let _ = self.socket.close(cf);
-        let timeout = Instant::now() + Duration::from_secs(10);
-        while self.socket.write_pending().is_ok() && timeout < Instant::now()
-        {
-            std::thread::yield_now()
-        }
+        Self::new()
     }
 }
 
-impl SockleServer for SimpleSockleServer
+impl SimpleSockleServer
 {
-    fn listen<F: Fn(String, Box<dyn Fn(String)>) -> Result<()> + Send + Sync + 'static>(
+    pub fn new() -> Self
+    {
+        Self::with_config(SockleServerConfig::default())
+    }
+
+    pub fn with_config(config: SockleServerConfig) -> Self
+    {
+        SimpleSockleServer { shared:        Default::default(),
+                             config:        Arc::new(config),
+                             listen_thread: None }
+    }
+
+    /// Calls f on the worker thread each time a client completes the
+    /// handshake, before any of its messages are handled
+    ///
+    /// The `ConnectionHandle` can be stored to send to that client later,
+    /// independent of incoming messages. Replaces any previous hook.
+    pub fn on_connect<F: Fn(&ConnectionInfo, ConnectionHandle) + Send + Sync + 'static>(&self, f: F)
+    {
+        *self.shared.on_connect.write().unwrap() = Some(Arc::new(f));
+    }
+
+    /// Calls f on the worker thread when a connection ends, with the reason
+    /// it ended
+    ///
+    /// The `ConnectionInfo` is as of the last message received. Also called
+    /// for connections closed straight after the handshake because the
+    /// server is shutting down. Replaces any previous hook.
+    pub fn on_disconnect<F: Fn(&ConnectionInfo, DisconnectReason) + Send + Sync + 'static>(&self, f: F)
+    {
+        *self.shared.on_disconnect.write().unwrap() = Some(Arc::new(f));
+    }
+
+    /// Calls f when the message handler returns an error, sending the text
+    /// it returns to the client before the close frame
+    ///
+    /// Lets clients that expect a structured error, e.g.
+    /// `{"error": "..."}`, get one. Return None to only send the close
+    /// frame, which carries the error's text either way. Replaces any
+    /// previous hook.
+    pub fn on_handler_error<F: Fn(&anyhow::Error) -> Option<String> + Send + Sync + 'static>(&self, f: F)
+    {
+        *self.shared.on_error.write().unwrap() = Some(Arc::new(f));
+    }
+
+    /// Calls f for each client a text broadcast is about to be sent to, on
+    /// that client's worker thread
+    ///
+    /// Return the message to send, changed as needed, or None to skip that
+    /// client. Applies to `send` and `send_where`, not to replies or binary
+    /// data. Replaces any previous hook.
+    pub fn on_outgoing<F: Fn(&ConnectionInfo, String) -> Option<String> + Send + Sync + 'static>(&self, f: F)
+    {
+        *self.shared.on_outgoing.write().unwrap() = Some(Arc::new(f));
+    }
+
+    /// Calls f with each upgrade request and the response about to be sent,
+    /// on the worker thread doing the handshake
+    ///
+    /// f can add headers to the response, e.g. a session cookie or the
+    /// chosen Sec-WebSocket-Protocol. Not called for requests rejected by
+    /// `SockleServerConfig::allowed_origins`. Replaces any previous hook.
+    pub fn on_handshake<F: Fn(&Request, &mut Response) + Send + Sync + 'static>(&self, f: F)
+    {
+        *self.shared.on_handshake.write().unwrap() = Some(Arc::new(f));
+    }
+
+    /// Handle that can shut the server down from another thread
+    pub fn handle(&self) -> SockleServerHandle
+    {
+        SockleServerHandle { shared: self.shared.clone(),
+                             config: self.config.clone() }
+    }
+
+    /// Same as listen, but runs the accept loop on the calling thread
+    ///
+    /// Returns once `shutdown` is called on a handle from `handle`.
+    pub fn listen_blocking<F: Fn(String, Box<dyn Fn(String)>) -> Result<()> + Send + Sync + 'static>(
         &mut self,
         listen_address: &str,
         on_message: F)
         -> Result<()>
     {
-        let server = TcpListener::bind(listen_address)?;
-        server.set_nonblocking(true)?;
-        let on_message: OnMessageFn = Arc::new(on_message);
-        let senders = self.thread_senders.clone();
-        let (thread_ctrl_s, thread_ctrl_r) = std::sync::mpsc::channel();
-        self.thread_ctrl = Some(thread_ctrl_s);
-        std::thread::Builder::new().name("Sockle Server Connection Listener".to_string()).spawn(move || {
-            for stream in server.incoming()
-            {
-                match stream
-                {
-                    Ok(s) =>
-                    {
-                        let on_message_t = on_message.clone();
-                        let senders2 = senders.clone();
-                        std::thread::Builder::new().name("Sockle Server Client Connection".to_string()).spawn(move || {
-                            match tungstenite::accept(s)
-                            {
-                                Ok(socket) =>
-                                {
-                                    let r = {
-                                        let mut s = senders2.lock().unwrap();
-                                        let (sender, r) = std::sync::mpsc::channel();
-                                        s.push(sender);
-                                        r
-                                    };
-                                    Conn::new(socket, r, on_message_t).on_accept();
-                                }
-                                Err(e) =>
-                                {
-                                    log::error!("Error accepting incoming stream: {e}");
-                                }
-                            }
-                        }).unwrap();
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
-                    {
-                        std::thread::sleep(Duration::from_millis(15));
-                    }
-                    Err(e) =>
-                    {
-                        log::error!("Error opening incoming stream: {e}");
-                    }
-                }
-
-                if matches!(thread_ctrl_r.try_recv(), Err(TryRecvError::Disconnected) | Ok(_))
-                {
-                    log::debug!("Server shutdown requested, ending listen thread");
-                    break;
-                }
-
-            }
-            log::info!("Sockle server has shutdown");
-        })?;
+        let on_message: OnMessageFn = Arc::new(move |message, reply, _: &ConnectionHandle| {
+            on_message(message, Box::new(move |s| reply(Reply::Text(s))))
+        });
+        self.start(TcpListener::bind(listen_address)?, on_message, false)?.run();
         Ok(())
     }
 
-    fn send(&self, msg: String)
+    /// Same as listen, but the handler gets the client's `ConnectionHandle`
+    /// instead of a reply closure
+    ///
+    /// For handlers that hand work to another thread and reply once it
+    /// completes, `ConnectionHandle::reply` can be called at any time,
+    /// from any thread. Replies go through the connection's queue, so they
+    /// can arrive after messages broadcast meanwhile and, unlike immediate
+    /// replies, count towards the queue limits.
+    pub fn listen_deferred<F: Fn(String, ConnectionHandle) -> Result<()> + Send + Sync + 'static>(
+        &mut self,
+        listen_address: &str,
+        on_message: F)
+        -> Result<()>
     {
-        for s in self.thread_senders.lock().unwrap().iter()
-        {
-            let _ = s.send(SockleServerMessage::Send(msg.clone()));
-        }
+        self.spawn(TcpListener::bind(listen_address)?,
+                   Arc::new(move |message, _, handle: &ConnectionHandle| on_message(message, handle.clone())),
+                   true)
     }
 
-    fn shutdown(&self) -> Result<()>
+    /// Serves a connection over an already open stream, as if it had just
+    /// been accepted
+    ///
+    /// The stream goes through the websocket handshake and is then handled
+    /// like any other client. Needs the server to be listening.
+    pub fn serve_stream<T: SockleTransport>(&self, stream: T) -> Result<()>
     {
-        for s in self.thread_senders.lock().unwrap().iter()
+        let pool = match self.shared.pool.lock().unwrap().upgrade()
         {
-            let _ = s.send(SockleServerMessage::Shutdown);
-        }
-        let tc = self.thread_ctrl.as_ref().unwrap();
-        if let Err(e) = tc.send(())
+            Some(pool) => pool,
+            None => anyhow::bail!("Server not listening")
+        };
+        if self.shared.stopping.load(Ordering::SeqCst)
         {
-            let err = format!("Unable to signal listen thread to end: {e}");
-            log::error!("{err}");
-            anyhow::bail!(err);
+            anyhow::bail!("Server shutting down");
         }
-        while let Ok(_) = tc.send(())
+        if matches!(self.config.max_connections, Some(max) if pool.load() >= max)
         {
-            std::thread::yield_now();
+            anyhow::bail!("Connection limit reached");
         }
+        pool.dispatch(Box::new(stream));
+        Ok(())
+    }
+
+    /// Client end of an in-memory connection to the server
+    ///
+    /// Runs the server side without a socket, e.g. to test handlers without
+    /// allocating ports. Drive it with `SimpleSockleClient::connect_stream`
+    /// or `tungstenite::client`, the url only fills in the upgrade request.
+    /// Needs the server to be listening, on "127.0.0.1:0" if only in-memory
+    /// connections are wanted.
+    pub fn connect_in_memory(&self) -> Result<MemoryStream>
+    {
+        let (client, server) = MemoryStream::pair();
+        self.serve_stream(server)?;
+        Ok(client)
+    }
+
+    /// Starts serving listener on its own thread
+    fn spawn(&mut self, listener: TcpListener, on_message: OnMessageFn, deferred: bool) -> Result<()>
+    {
+        let listener = self.start(listener, on_message, deferred)?;
+        let thread = self.config
+                         .thread_builder("Connection Listener")
+                         .spawn(move || listener.run())?;
+        self.listen_thread = Some(thread);
         Ok(())
     }
 
+    /// Prepares the worker pool and accept loop for listener
+    ///
+    /// deferred tells whether on_message may answer after returning.
+    fn start(&mut self, server: TcpListener, on_message: OnMessageFn, deferred: bool) -> Result<Listener>
+    {
+        server.set_nonblocking(true)?;
+        self.shared.stopping.store(false, Ordering::SeqCst);
+        self.shared.deferred.store(deferred, Ordering::SeqCst);
+        *self.shared.drain_deadline.lock().unwrap() = None;
+        let pool = Arc::new(WorkerPool::new(on_message, self.shared.clone(), self.config.clone())?);
+        *self.shared.pool.lock().unwrap() = Arc::downgrade(&pool);
+        let (thread_ctrl_s, thread_ctrl_r) = std::sync::mpsc::channel();
+        *self.shared.thread_ctrl.lock().unwrap() = Some(thread_ctrl_s);
+        Ok(Listener::new(server, pool, self.shared.clone(), thread_ctrl_r, self.config.clone()))
+    }
+}
+
+pub type OnMessageFn = Arc<dyn Fn(String, Box<dyn Fn(Reply)>, &ConnectionHandle) -> Result<()> + Send + Sync>;
+pub(crate) type OnConnectFn = Arc<dyn Fn(&ConnectionInfo, ConnectionHandle) + Send + Sync>;
+pub(crate) type OnDisconnectFn = Arc<dyn Fn(&ConnectionInfo, DisconnectReason) + Send + Sync>;
+pub(crate) type OnErrorFn = Arc<dyn Fn(&anyhow::Error) -> Option<String> + Send + Sync>;
+pub(crate) type OnDecodeErrorFn = Arc<dyn Fn(&str, &crate::CodecError) -> Option<String> + Send + Sync>;
+pub(crate) type OnOutgoingFn = Arc<dyn Fn(&ConnectionInfo, String) -> Option<String> + Send + Sync>;
+pub(crate) type OnHandshakeFn = Arc<dyn Fn(&Request, &mut Response) + Send + Sync>;
+
+/// Closes connections and stops the listen thread, waiting at most
+/// `DROP_JOIN_TIMEOUT` for it to end
+impl Drop for SimpleSockleServer
+{
+    fn drop(&mut self)
+    {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        self.shared.registry.broadcast(|| SockleServerMessage::Shutdown);
+        // Dropping the sender ends the listen loop on its next pass
+        drop(self.shared.thread_ctrl.lock().unwrap().take());
+
+        if let Some(thread) = self.listen_thread.take()
+        {
+            let deadline = Instant::now() + DROP_JOIN_TIMEOUT;
+            while !thread.is_finished() && Instant::now() < deadline
+            {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            if !thread.is_finished()
+            {
+                log::warn!("Listen thread still running after server dropped");
+            }
+        }
+    }
+}
+
+impl SockleServer for SimpleSockleServer
+{
+    fn listen_with_reply<F: Fn(String, Box<dyn Fn(Reply)>) -> Result<()> + Send + Sync + 'static>(
+        &mut self,
+        listen_address: &str,
+        on_message: F)
+        -> Result<()>
+    {
+        self.listen_from_with_reply(TcpListener::bind(listen_address)?, on_message)
+    }
+
+    fn listen_from_with_reply<F: Fn(String, Box<dyn Fn(Reply)>) -> Result<()> + Send + Sync + 'static>(
+        &mut self,
+        listener: TcpListener,
+        on_message: F)
+        -> Result<()>
+    {
+        self.spawn(listener,
+                   Arc::new(move |message, reply, _: &ConnectionHandle| on_message(message, reply)),
+                   false)
+    }
+
+    fn send(&self, msg: String)
+    {
+        self.send_where(|_| true, msg);
+    }
+
+    fn broadcast_others(&self, except: u64, msg: String) -> usize
+    {
+        self.send_where(|c| c.id != except, msg)
+    }
+
+    fn send_with_priority(&self, msg: String, priority: Priority)
+    {
+        self.shared
+            .registry
+            .send_where(|_| true, SockleServerMessage::Send(msg.into(), priority), &self.config);
+    }
+
+    fn send_binary(&self, data: Vec<u8>)
+    {
+        self.shared
+            .registry
+            .send_where(|_| true, SockleServerMessage::SendBinary(data.into()), &self.config);
+    }
+
+    fn send_where<P: Fn(&ConnectionInfo) -> bool>(&self, pred: P, msg: String) -> usize
+    {
+        self.shared
+            .registry
+            .send_where(pred, SockleServerMessage::Send(msg.into(), Priority::Normal), &self.config)
+    }
+
+    fn broadcast_await_acks(&self, msg: String, expected: usize, timeout: Duration) -> usize
+    {
+        let id = self.shared.acks.start();
+        self.shared
+            .registry
+            .send_where(|_| true, SockleServerMessage::SendWithAck(msg.into(), id), &self.config);
+        self.shared.acks.wait(id, expected, timeout)
+    }
+
+    fn shutdown(&self) -> Result<()>
+    {
+        self.shared.shutdown()
+    }
+
+    fn drain(&self, grace: Duration) -> Result<()>
+    {
+        let deadline = Instant::now() + grace;
+        *self.shared.drain_deadline.lock().unwrap() = Some(deadline);
+        // Refuses handshakes still in progress
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        self.shared.registry.broadcast(|| SockleServerMessage::Drain(deadline));
+        self.shared.stop_listening()
+    }
+
     fn connection_count(&self) -> usize
     {
-        self.thread_senders.lock().unwrap().len()
+        self.shared.registry.len()
+    }
+
+    fn wait_for_connections(&self, count: usize, timeout: Duration) -> bool
+    {
+        let deadline = Instant::now() + timeout;
+        while self.shared.registry.len() < count
+        {
+            if Instant::now() >= deadline
+            {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    fn stats(&self) -> SockleServerStats
+    {
+        let rate = self.config.max_message_rate;
+        SockleServerStats { connections:      self.shared.registry.len(),
+                            queued_bytes:     self.shared.registry.queued_bytes(),
+                            max_message_rate: rate,
+                            rate_utilization: rate.map(|r| self.shared.throttle.utilization(r))
+                                                  .unwrap_or_default(),
+                            uptime:           self.shared.registry.uptime() }
+    }
+
+    fn connection_count_by_family(&self) -> (usize, usize)
+    {
+        self.shared.registry.len_by_family()
+    }
+
+    fn connections(&self) -> Vec<ConnectionInfo>
+    {
+        self.shared.registry.infos()
+    }
+
+    fn connection(&self, id: u64) -> Option<ConnectionInfo>
+    {
+        self.shared.registry.info(id)
+    }
+
+    fn join_room(&self, id: u64, room: &str) -> bool
+    {
+        self.shared.registry.join_room(id, room)
+    }
+
+    fn leave_room(&self, id: u64, room: &str) -> bool
+    {
+        self.shared.registry.leave_room(id, room)
+    }
+
+    fn send_to_room(&self, room: &str, msg: String) -> usize
+    {
+        self.shared
+            .registry
+            .send_to_room(room, SockleServerMessage::Send(msg.into(), Priority::Normal), &self.config)
+    }
+
+    fn room_members(&self, room: &str) -> Vec<u64>
+    {
+        self.shared.registry.room_members(room)
     }
 }
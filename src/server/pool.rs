@@ -0,0 +1,224 @@
+use super::*;
+use conn::{Conn, ConnStatus};
+use watchdog::Watchdog;
+use handshake::{Handshake, HandshakeStatus};
+use std::{panic::AssertUnwindSafe,
+          rc::Rc,
+          sync::{atomic::{AtomicUsize, Ordering},
+                 mpsc::{Receiver, RecvTimeoutError, Sender}}};
+
+/// Number of workers used when the parallelism of the machine is unknown
+const FALLBACK_WORKER_COUNT: usize = 4;
+
+struct Worker
+{
+    incoming: Sender<Box<dyn SockleTransport>>,
+    load:     Arc<AtomicUsize>,
+    /// Cleared when the worker thread ends, so nothing more is dispatched to it
+    alive:    Arc<AtomicBool>
+}
+
+/// Clears a worker's alive flag when its thread ends, even by unwinding
+struct AliveGuard(Arc<AtomicBool>);
+
+impl Drop for AliveGuard
+{
+    fn drop(&mut self)
+    {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Fixed set of threads that multiplex all client connections
+///
+/// Each worker owns a set of connections and polls them in turn, so the
+/// number of threads does not grow with the number of clients.
+pub(crate) struct WorkerPool
+{
+    workers: Vec<Worker>
+}
+
+impl WorkerPool
+{
+    /// Spawns the configured number of workers, by default one per core
+    pub(crate) fn new(on_message: OnMessageFn,
+                      shared: Arc<Shared>,
+                      config: Arc<SockleServerConfig>)
+                      -> Result<Self>
+    {
+        let size = config.worker_threads.unwrap_or_else(|| {
+                                             std::thread::available_parallelism().map(|n| n.get())
+                                                                                 .unwrap_or(FALLBACK_WORKER_COUNT)
+                                         })
+                                         .max(1);
+        let mut workers = Vec::with_capacity(size);
+        for i in 0..size
+        {
+            let (incoming, incoming_r) = std::sync::mpsc::channel();
+            let load = Arc::new(AtomicUsize::new(0));
+            let load_t = load.clone();
+            let alive = Arc::new(AtomicBool::new(true));
+            let alive_t = AliveGuard(alive.clone());
+            let on_message_t = on_message.clone();
+            let shared_t = shared.clone();
+            let config_t = config.clone();
+            let thread = config.thread_builder(&format!("Worker {i}"))
+                               .spawn(move || {
+                                   let _alive = alive_t;
+                                   WorkerPool::run(incoming_r, load_t, on_message_t, shared_t, config_t)
+                               })?;
+            shared.workers.lock().unwrap().push(thread);
+            workers.push(Worker { incoming, load, alive });
+        }
+        Ok(Self { workers })
+    }
+
+    /// Number of connections being served, including those mid handshake
+    pub(crate) fn load(&self) -> usize
+    {
+        self.workers.iter().map(|w| w.load.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Hands a newly accepted stream to the least loaded worker still running
+    pub(crate) fn dispatch(&self, stream: Box<dyn SockleTransport>)
+    {
+        let Some(worker) = self.workers
+                               .iter()
+                               .filter(|w| w.alive.load(Ordering::SeqCst))
+                               .min_by_key(|w| w.load.load(Ordering::Relaxed))
+        else
+        {
+            log::error!("No worker threads left, dropping incoming stream");
+            return;
+        };
+        worker.load.fetch_add(1, Ordering::Relaxed);
+        if worker.incoming.send(stream).is_err()
+        {
+            log::error!("Worker thread has ended, dropping incoming stream");
+            worker.load.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Worker loop, runs until the pool is dropped and all its connections have closed
+    fn run(incoming: Receiver<Box<dyn SockleTransport>>,
+           load: Arc<AtomicUsize>,
+           on_message: OnMessageFn,
+           shared: Arc<Shared>,
+           config: Arc<SockleServerConfig>)
+    {
+        let mut conns: Vec<Conn> = Vec::new();
+        let mut handshakes: Vec<Handshake> = Vec::new();
+        let mut accepting = true;
+        let watchdog = config.handler_timeout.map(|t| Rc::new(Watchdog::new(t, config.clone())));
+        // Finishes a handshake's upgrade into a connection, panics raised by
+        // callbacks are contained to that connection
+        let advance = |status: HandshakeStatus, handshakes: &mut Vec<Handshake>, conns: &mut Vec<Conn>| {
+            let accepted = match status
+            {
+                HandshakeStatus::Pending(h) =>
+                {
+                    handshakes.push(h);
+                    return;
+                }
+                HandshakeStatus::Done(upgraded) => std::panic::catch_unwind(AssertUnwindSafe(|| {
+                                                       Conn::accept(upgraded,
+                                                                    &shared,
+                                                                    on_message.clone(),
+                                                                    watchdog.clone(),
+                                                                    config.clone())
+                                                   })).unwrap_or_else(|_| {
+                                                          log::error!("Panic while accepting a connection, dropping it");
+                                                          None
+                                                      }),
+                HandshakeStatus::Failed => None
+            };
+            match accepted
+            {
+                Some(c) => conns.push(c),
+                None =>
+                {
+                    load.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        };
+        loop
+        {
+            let mut busy = false;
+            // Upgrades run alongside established connections, a client that
+            // stalls mid handshake holds up nothing but itself
+            for h in std::mem::take(&mut handshakes)
+            {
+                let status = std::panic::catch_unwind(AssertUnwindSafe(|| h.advance(&shared)))
+                                 .unwrap_or_else(|_| {
+                                     log::error!("Panic during handshake, dropping incoming stream");
+                                     HandshakeStatus::Failed
+                                 });
+                advance(status, &mut handshakes, &mut conns);
+            }
+            // A panic is contained to the connection that raised it, rather
+            // than unwinding every other connection on this worker
+            conns.retain_mut(|c| match std::panic::catch_unwind(AssertUnwindSafe(|| c.poll()))
+                                                 .unwrap_or_else(|_| {
+                                                     log::error!("Panic while polling a connection, dropping it");
+                                                     ConnStatus::Closed
+                                                 })
+                             {
+                                 ConnStatus::Busy =>
+                                 {
+                                     busy = true;
+                                     true
+                                 }
+                                 ConnStatus::Idle => true,
+                                 ConnStatus::Closed =>
+                                 {
+                                     load.fetch_sub(1, Ordering::Relaxed);
+                                     false
+                                 }
+                             });
+
+            if !accepting
+            {
+                if conns.is_empty() && handshakes.is_empty()
+                {
+                    break;
+                }
+                if !busy
+                {
+                    std::thread::sleep(config.poll_interval);
+                }
+                continue;
+            }
+
+            // Wait for new streams while idle so they are picked up promptly
+            let next = if busy
+            {
+                incoming.try_recv().map_err(|e| match e
+                                   {
+                                       TryRecvError::Empty => RecvTimeoutError::Timeout,
+                                       TryRecvError::Disconnected => RecvTimeoutError::Disconnected
+                                   })
+            }
+            else
+            {
+                incoming.recv_timeout(config.poll_interval)
+            };
+            match next
+            {
+                Ok(stream) =>
+                {
+                    let status = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                                     Handshake::start(stream, &shared, config.clone())
+                                 })).unwrap_or_else(|_| {
+                                        log::error!("Panic during handshake, dropping incoming stream");
+                                        HandshakeStatus::Failed
+                                    });
+                    advance(status, &mut handshakes, &mut conns);
+                }
+                Err(RecvTimeoutError::Timeout) =>
+                {}
+                Err(RecvTimeoutError::Disconnected) => accepting = false
+            }
+        }
+        log::debug!("Sockle server worker has ended");
+    }
+}
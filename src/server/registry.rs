@@ -0,0 +1,661 @@
+use super::*;
+use std::{cell::RefCell,
+          collections::{HashMap, HashSet},
+          net::{IpAddr, SocketAddr},
+          sync::{atomic::{AtomicU64, AtomicUsize, Ordering},
+                 mpsc::{Receiver, SendError},
+                 Weak}};
+
+/// Details of a connected client
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo
+{
+    /// Unique for the lifetime of the server
+    pub id:              u64,
+    pub peer_addr:       SocketAddr,
+    /// Address the client connected to, tells which interface accepted it
+    /// when bound to a wildcard address
+    pub local_addr:      SocketAddr,
+    pub connected_at:    Instant,
+    /// When a frame of any kind was last received, including pongs
+    pub last_message_at: Instant,
+    /// Broadcasts skipped because the client had fallen too far behind,
+    /// see `SockleServerConfig::broadcast_queue_limit`
+    pub dropped:         u64,
+    /// Metadata attached with `ConnectionHandle::set_label`, never sent to
+    /// the client
+    pub labels:          HashMap<String, String>,
+    /// Sec-WebSocket-Protocol agreed in the handshake, see
+    /// `SockleServerConfig::subprotocols`
+    pub subprotocol:     Option<String>,
+    /// Rooms joined with `SockleServer::join_room` or
+    /// `ConnectionHandle::join_room`, left automatically on disconnect
+    pub rooms:           HashSet<String>
+}
+
+impl ConnectionInfo
+{
+    /// How long the client has been connected
+    pub fn uptime(&self) -> Duration
+    {
+        self.connected_at.elapsed()
+    }
+
+    /// Whether the client connected over IPv6
+    ///
+    /// IPv4 clients accepted on a dual-stack IPv6 socket show up as
+    /// IPv4-mapped addresses and count as IPv4.
+    pub fn is_ipv6(&self) -> bool
+    {
+        match self.peer_addr.ip()
+        {
+            IpAddr::V4(_) => false,
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().is_none()
+        }
+    }
+}
+
+thread_local! {
+    /// Connection whose message is being handled on this thread, see
+    /// `ConnectionHandle::current`
+    static CURRENT: RefCell<Option<ConnectionHandle>> = const { RefCell::new(None) };
+}
+
+/// Cloneable handle for sending to one client, see `SimpleSockleServer::on_connect`
+///
+/// Stays valid after the connection ends, sends then fail with
+/// `SocketDisconnected`.
+#[derive(Clone)]
+pub struct ConnectionHandle
+{
+    id:          u64,
+    sender:      CtrlSender,
+    pending:     Arc<Pending>,
+    connections: Weak<Mutex<Connections>>
+}
+
+impl ConnectionHandle
+{
+    /// Id of the connection, matches `ConnectionInfo::id`
+    pub fn id(&self) -> u64
+    {
+        self.id
+    }
+
+    /// Handle of the client whose message is being handled, None when not
+    /// called from a message handler
+    ///
+    /// For handlers that aren't passed the handle, e.g. those given to
+    /// `listen` or `SockleHandler::on_message`, to tell who sent the
+    /// message, say for `SockleServer::broadcast_others`.
+    pub fn current() -> Option<ConnectionHandle>
+    {
+        CURRENT.with(|c| c.borrow().clone())
+    }
+
+    /// Runs a message handler with this as the current connection
+    pub(crate) fn enter<R>(&self, f: impl FnOnce() -> R) -> R
+    {
+        /// Puts back the previous connection, even if the handler panics
+        struct Restore(Option<ConnectionHandle>);
+        impl Drop for Restore
+        {
+            fn drop(&mut self)
+            {
+                CURRENT.with(|c| *c.borrow_mut() = self.0.take());
+            }
+        }
+
+        let _restore = Restore(CURRENT.with(|c| c.replace(Some(self.clone()))));
+        f()
+    }
+
+    /// Sends a message to this client
+    ///
+    /// Never dropped, the broadcast queue limits don't apply.
+    pub fn send(&self, msg: String) -> Result<(), SimpleSockleError>
+    {
+        self.push(SockleServerMessage::Send(msg.into(), Priority::Normal))
+    }
+
+    /// Sends a message to this client in the given tier, see
+    /// `SockleServer::send_with_priority`
+    pub fn send_with_priority(&self, msg: String, priority: Priority) -> Result<(), SimpleSockleError>
+    {
+        self.push(SockleServerMessage::Send(msg.into(), priority))
+    }
+
+    /// Sends binary data to this client
+    pub fn send_binary(&self, data: Vec<u8>) -> Result<(), SimpleSockleError>
+    {
+        self.push(SockleServerMessage::SendBinary(data.into()))
+    }
+
+    /// Replies to this client outside of the message handler, see
+    /// `SimpleSockleServer::listen_deferred`
+    pub fn reply(&self, reply: Reply) -> Result<(), SimpleSockleError>
+    {
+        match reply
+        {
+            Reply::Text(t) => self.send(t),
+            Reply::Binary(b) => self.send_binary(b),
+            Reply::Close(cf) => self.push(SockleServerMessage::Close(cf))
+        }
+    }
+
+    /// Whether sends to this connection are waiting in its queue
+    pub(crate) fn has_queued(&self) -> bool
+    {
+        self.pending.messages.load(Ordering::Relaxed) > 0
+    }
+
+    /// Current details of this connection, None once it has closed
+    ///
+    /// Lets a handler holding the handle look up e.g. the agreed
+    /// subprotocol.
+    pub fn info(&self) -> Option<ConnectionInfo>
+    {
+        let connections = self.connections.upgrade()?;
+        let connections = connections.lock().unwrap();
+        connections.entries.get(&self.id).map(|c| c.info.clone())
+    }
+
+    /// Attaches a label to this connection, replacing any with the same key
+    ///
+    /// Shows up in `ConnectionInfo::labels`, so `send_where` can select
+    /// connections by it.
+    pub fn set_label(&self, key: impl Into<String>, value: impl Into<String>) -> Result<(), SimpleSockleError>
+    {
+        self.update(|info| {
+                info.labels.insert(key.into(), value.into());
+            })
+    }
+
+    /// Adds this connection to room, see `SockleServer::join_room`
+    pub fn join_room(&self, room: impl Into<String>) -> Result<(), SimpleSockleError>
+    {
+        self.locked(|c| c.join_room(self.id, room.into()).then_some(()))
+    }
+
+    /// Removes this connection from room, returning whether it was in it
+    pub fn leave_room(&self, room: &str) -> Result<bool, SimpleSockleError>
+    {
+        self.locked(|c| c.leave_room(self.id, room))
+    }
+
+    /// Runs f on this connection's details, failing if it has closed
+    fn update<R>(&self, f: impl FnOnce(&mut ConnectionInfo) -> R) -> Result<R, SimpleSockleError>
+    {
+        self.locked(|c| c.entries.get_mut(&self.id).map(|e| f(&mut e.info)))
+    }
+
+    /// Runs f with the registry locked, failing if it or f finds this
+    /// connection has closed
+    fn locked<R>(&self, f: impl FnOnce(&mut Connections) -> Option<R>) -> Result<R, SimpleSockleError>
+    {
+        let connections = self.connections
+                              .upgrade()
+                              .ok_or(SimpleSockleError::SocketDisconnected)?;
+        let mut connections = connections.lock().unwrap();
+        f(&mut connections).ok_or(SimpleSockleError::SocketDisconnected)
+    }
+
+    fn push(&self, msg: SockleServerMessage) -> Result<(), SimpleSockleError>
+    {
+        let len = msg.data_len().unwrap_or_default();
+        self.pending.add(len);
+        self.sender.send(msg).map_err(|_| {
+                                 self.pending.remove(len);
+                                 SimpleSockleError::SocketDisconnected
+                             })
+    }
+}
+
+/// Data messages queued for a connection but not yet taken by it
+pub(crate) struct Pending
+{
+    messages: AtomicUsize,
+    bytes:    AtomicUsize,
+    /// Bytes queued across all connections, shared with the registry
+    total:    Arc<AtomicUsize>
+}
+
+impl Pending
+{
+    fn add(&self, bytes: usize)
+    {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn remove(&self, bytes: usize)
+    {
+        self.release(1, bytes);
+    }
+
+    fn release(&self, messages: usize, bytes: usize)
+    {
+        self.messages.fetch_sub(messages, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.total.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+pub(crate) struct ConnectionEntry
+{
+    pub(crate) info:    ConnectionInfo,
+    pub(crate) sender:  CtrlSender,
+    pub(crate) pending: Arc<Pending>
+}
+
+/// Sending end of a connection's ctrl channel, one queue per priority
+#[derive(Clone)]
+pub(crate) struct CtrlSender
+{
+    high:   Sender<SockleServerMessage>,
+    normal: Sender<SockleServerMessage>
+}
+
+impl CtrlSender
+{
+    pub(crate) fn send(&self, msg: SockleServerMessage) -> Result<(), SendError<SockleServerMessage>>
+    {
+        match msg.priority()
+        {
+            Priority::High => self.high.send(msg),
+            Priority::Normal => self.normal.send(msg)
+        }
+    }
+}
+
+/// Receiving end of a connection's ctrl channel
+pub(crate) struct CtrlReceiver
+{
+    high:          Receiver<SockleServerMessage>,
+    normal:        Receiver<SockleServerMessage>,
+    pending:       Arc<Pending>,
+    /// Data messages taken but not yet written out, still counted as
+    /// pending, see `hold`
+    held_messages: usize,
+    held_bytes:    usize
+}
+
+impl CtrlReceiver
+{
+    /// Next message, high priority ones first
+    pub(crate) fn try_recv(&self) -> Result<SockleServerMessage, TryRecvError>
+    {
+        let msg = match self.high.try_recv()
+        {
+            Ok(msg) => msg,
+            Err(_) => self.normal.try_recv()?
+        };
+        if let Some(len) = msg.data_len()
+        {
+            self.pending.remove(len);
+        }
+        Ok(msg)
+    }
+
+    /// Keeps counting a data message taken from the channel as pending
+    /// while the socket hasn't taken it, so a client that stops reading
+    /// still runs into the queue limits
+    pub(crate) fn hold(&mut self, bytes: usize)
+    {
+        self.pending.add(bytes);
+        self.held_messages += 1;
+        self.held_bytes += bytes;
+    }
+
+    /// Releases everything held once the socket has taken it
+    pub(crate) fn release(&mut self)
+    {
+        self.pending.release(self.held_messages, self.held_bytes);
+        self.held_messages = 0;
+        self.held_bytes = 0;
+    }
+}
+
+/// Releases whatever the connection didn't get to from the queued totals
+impl Drop for CtrlReceiver
+{
+    fn drop(&mut self)
+    {
+        self.release();
+        while self.try_recv().is_ok()
+        {}
+    }
+}
+
+/// Live connections and the members of each room, kept under one lock so
+/// the two always agree
+#[derive(Default)]
+struct Connections
+{
+    entries: HashMap<u64, ConnectionEntry>,
+    /// Rooms with at least one member
+    rooms:   HashMap<String, HashSet<u64>>
+}
+
+impl Connections
+{
+    /// Adds connection id to room, false if there is no such connection
+    fn join_room(&mut self, id: u64, room: String) -> bool
+    {
+        let Some(entry) = self.entries.get_mut(&id)
+        else
+        {
+            return false;
+        };
+        entry.info.rooms.insert(room.clone());
+        self.rooms.entry(room).or_default().insert(id);
+        true
+    }
+
+    /// Removes connection id from room, returning whether it was in it,
+    /// None if there is no such connection
+    fn leave_room(&mut self, id: u64, room: &str) -> Option<bool>
+    {
+        let left = self.entries.get_mut(&id)?.info.rooms.remove(room);
+        if left
+        {
+            self.unindex(id, room);
+        }
+        Some(left)
+    }
+
+    /// Removes connection id along with its room memberships
+    fn remove(&mut self, id: u64)
+    {
+        if let Some(entry) = self.entries.remove(&id)
+        {
+            for room in &entry.info.rooms
+            {
+                self.unindex(id, room);
+            }
+        }
+    }
+
+    fn unindex(&mut self, id: u64, room: &str)
+    {
+        if let Some(members) = self.rooms.get_mut(room)
+        {
+            members.remove(&id);
+            if members.is_empty()
+            {
+                self.rooms.remove(room);
+            }
+        }
+    }
+}
+
+/// Live connections, added once the handshake completes and removed when
+/// the connection ends
+#[derive(Default)]
+pub(crate) struct Registry
+{
+    next_id:      AtomicU64,
+    connections:  Arc<Mutex<Connections>>,
+    /// Bytes of data messages queued across all connections
+    queued_bytes: Arc<AtomicUsize>
+}
+
+impl Registry
+{
+    /// Adds a connection, returning its id and ctrl channel
+    pub(crate) fn register(&self,
+                           peer_addr: SocketAddr,
+                           local_addr: SocketAddr,
+                           subprotocol: Option<String>)
+                           -> (u64, CtrlReceiver)
+    {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (high, high_r) = std::sync::mpsc::channel();
+        let (normal, normal_r) = std::sync::mpsc::channel();
+        let sender = CtrlSender { high, normal };
+        let pending = Arc::new(Pending { messages: AtomicUsize::new(0),
+                                         bytes:    AtomicUsize::new(0),
+                                         total:    self.queued_bytes.clone() });
+        let now = Instant::now();
+        let info = ConnectionInfo { id,
+                                    peer_addr,
+                                    local_addr,
+                                    connected_at: now,
+                                    last_message_at: now,
+                                    dropped: 0,
+                                    labels: HashMap::new(),
+                                    subprotocol,
+                                    rooms: HashSet::new() };
+        self.connections
+            .lock()
+            .unwrap()
+            .entries
+            .insert(id,
+                    ConnectionEntry { info,
+                                      sender,
+                                      pending: pending.clone() });
+        (id,
+         CtrlReceiver { high: high_r,
+                        normal: normal_r,
+                        pending,
+                        held_messages: 0,
+                        held_bytes: 0 })
+    }
+
+    pub(crate) fn remove(&self, id: u64)
+    {
+        self.connections.lock().unwrap().remove(id);
+    }
+
+    /// Records a frame received on a connection
+    pub(crate) fn touch(&self, id: u64, at: Instant)
+    {
+        if let Some(c) = self.connections.lock().unwrap().entries.get_mut(&id)
+        {
+            c.info.last_message_at = at;
+        }
+    }
+
+    pub(crate) fn handle(&self, id: u64) -> Option<ConnectionHandle>
+    {
+        self.connections
+            .lock()
+            .unwrap()
+            .entries
+            .get(&id)
+            .map(|c| ConnectionHandle { id,
+                                        sender: c.sender.clone(),
+                                        pending: c.pending.clone(),
+                                        connections: Arc::downgrade(&self.connections) })
+    }
+
+    /// Adds a connection to room, false if there is no such connection
+    pub(crate) fn join_room(&self, id: u64, room: &str) -> bool
+    {
+        self.connections.lock().unwrap().join_room(id, room.to_string())
+    }
+
+    /// Removes a connection from room, returning whether it was in it
+    pub(crate) fn leave_room(&self, id: u64, room: &str) -> bool
+    {
+        self.connections
+            .lock()
+            .unwrap()
+            .leave_room(id, room)
+            .unwrap_or_default()
+    }
+
+    /// Ids of the connections in room, in ascending order
+    pub(crate) fn room_members(&self, room: &str) -> Vec<u64>
+    {
+        let mut ids: Vec<u64> = self.connections
+                                    .lock()
+                                    .unwrap()
+                                    .rooms
+                                    .get(room)
+                                    .map(|m| m.iter().copied().collect())
+                                    .unwrap_or_default();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub(crate) fn info(&self, id: u64) -> Option<ConnectionInfo>
+    {
+        self.connections
+            .lock()
+            .unwrap()
+            .entries
+            .get(&id)
+            .map(|c| c.info.clone())
+    }
+
+    /// Sends a ctrl message to every connection
+    pub(crate) fn broadcast<F: Fn() -> SockleServerMessage>(&self, msg: F)
+    {
+        for c in self.connections.lock().unwrap().entries.values()
+        {
+            let _ = c.sender.send(msg());
+        }
+    }
+
+    /// Sends a data message to each connection matching pred, returning how
+    /// many it was queued for
+    ///
+    /// Connections over `broadcast_queue_limit` are skipped. If queueing for
+    /// the rest would go over `max_queued_bytes`, those with the most bytes
+    /// already waiting are skipped until it fits. Skipped connections have
+    /// their dropped count increased.
+    pub(crate) fn send_where<P>(&self, pred: P, msg: SockleServerMessage, config: &SockleServerConfig) -> usize
+        where P: Fn(&ConnectionInfo) -> bool
+    {
+        let mut connections = self.connections.lock().unwrap();
+        let ids: Vec<u64> = connections.entries
+                                       .values()
+                                       .filter(|c| pred(&c.info))
+                                       .map(|c| c.info.id)
+                                       .collect();
+        self.send_to(&mut connections.entries, ids, msg, config)
+    }
+
+    /// Sends a data message to each connection in room, with the same limits
+    /// as `send_where`
+    pub(crate) fn send_to_room(&self, room: &str, msg: SockleServerMessage, config: &SockleServerConfig) -> usize
+    {
+        let mut connections = self.connections.lock().unwrap();
+        let ids: Vec<u64> = match connections.rooms.get(room)
+        {
+            Some(members) => members.iter().copied().collect(),
+            None => return 0
+        };
+        self.send_to(&mut connections.entries, ids, msg, config)
+    }
+
+    fn send_to(&self,
+               entries: &mut HashMap<u64, ConnectionEntry>,
+               ids: Vec<u64>,
+               msg: SockleServerMessage,
+               config: &SockleServerConfig)
+               -> usize
+    {
+        let len = msg.data_len().unwrap_or_default();
+        let mut targets = Vec::new();
+        for id in ids
+        {
+            let Some(c) = entries.get_mut(&id)
+            else
+            {
+                continue;
+            };
+            let queued = c.pending.messages.load(Ordering::Relaxed);
+            if config.broadcast_queue_limit.is_some_and(|limit| queued >= limit)
+            {
+                c.info.dropped += 1;
+                continue;
+            }
+            targets.push(id);
+        }
+        if let Some(budget) = config.max_queued_bytes
+        {
+            let available = budget.saturating_sub(self.queued_bytes());
+            let fits = available.checked_div(len).unwrap_or(usize::MAX);
+            if fits < targets.len()
+            {
+                // Slowest first
+                targets.sort_by_key(|id| std::cmp::Reverse(entries[id].pending.bytes.load(Ordering::Relaxed)));
+                let shed = targets.len() - fits;
+                log::warn!("Outbound budget exhausted, dropping broadcast for {shed} connections");
+                for id in targets.drain(..shed)
+                {
+                    if let Some(c) = entries.get_mut(&id)
+                    {
+                        c.info.dropped += 1;
+                    }
+                }
+            }
+        }
+        let mut sent = 0;
+        for c in targets.iter().map(|id| &entries[id])
+        {
+            c.pending.add(len);
+            if c.sender.send(msg.clone()).is_ok()
+            {
+                sent += 1;
+            }
+            else
+            {
+                c.pending.remove(len);
+            }
+        }
+        sent
+    }
+
+    /// Bytes of data messages queued across all connections
+    pub(crate) fn queued_bytes(&self) -> usize
+    {
+        self.queued_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Connection details ordered by id
+    pub(crate) fn infos(&self) -> Vec<ConnectionInfo>
+    {
+        let mut infos: Vec<ConnectionInfo> = self.connections
+                                                 .lock()
+                                                 .unwrap()
+                                                 .entries
+                                                 .values()
+                                                 .map(|c| c.info.clone())
+                                                 .collect();
+        infos.sort_by_key(|i| i.id);
+        infos
+    }
+
+    /// Number of IPv4 and IPv6 connections
+    pub(crate) fn len_by_family(&self) -> (usize, usize)
+    {
+        let connections = self.connections.lock().unwrap();
+        let ipv6 = connections.entries.values().filter(|c| c.info.is_ipv6()).count();
+        (connections.entries.len() - ipv6, ipv6)
+    }
+
+    /// Uptime spread across all connections, None without any
+    pub(crate) fn uptime(&self) -> Option<UptimeStats>
+    {
+        let now = Instant::now();
+        let connections = self.connections.lock().unwrap();
+        let uptimes = connections.entries
+                                 .values()
+                                 .map(|c| now.saturating_duration_since(c.info.connected_at));
+        let (min, max, total) = uptimes.fold((Duration::MAX, Duration::ZERO, Duration::ZERO),
+                                             |(min, max, total), u| (min.min(u), max.max(u), total + u));
+        let count = u32::try_from(connections.entries.len()).ok().filter(|&c| c > 0)?;
+        Some(UptimeStats { min,
+                           max,
+                           mean: total / count })
+    }
+
+    pub(crate) fn len(&self) -> usize
+    {
+        self.connections.lock().unwrap().entries.len()
+    }
+}
@@ -0,0 +1,64 @@
+use super::*;
+
+/// Token bucket limiting messages handled across all connections, see
+/// `SockleServerConfig::max_message_rate`
+///
+/// Holds up to a second's worth of tokens, refilled continuously.
+#[derive(Default)]
+pub(crate) struct Throttle
+{
+    bucket: Mutex<Bucket>
+}
+
+#[derive(Default)]
+struct Bucket
+{
+    tokens:   f64,
+    /// None until first used, the bucket then starts full
+    refilled: Option<Instant>
+}
+
+impl Bucket
+{
+    fn refill(&mut self, rate: u32)
+    {
+        let now = Instant::now();
+        self.tokens = match self.refilled
+        {
+            Some(last) => (self.tokens + (now - last).as_secs_f64() * rate as f64).min(rate as f64),
+            None => rate as f64
+        };
+        self.refilled = Some(now);
+    }
+}
+
+impl Throttle
+{
+    /// Whether a message can be handled now
+    pub(crate) fn ready(&self, rate: u32) -> bool
+    {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        bucket.tokens >= 1.0
+    }
+
+    /// Uses up a token for a handled message
+    pub(crate) fn take(&self, rate: u32)
+    {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        bucket.tokens = (bucket.tokens - 1.0).max(0.0);
+    }
+
+    /// Fraction of the bucket used up, from 0 (idle) to 1 (throttling)
+    pub(crate) fn utilization(&self, rate: u32) -> f64
+    {
+        if rate == 0
+        {
+            return 1.0;
+        }
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(rate);
+        1.0 - bucket.tokens / rate as f64
+    }
+}
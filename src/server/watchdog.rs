@@ -0,0 +1,108 @@
+use super::*;
+use std::{cell::RefCell,
+          panic::AssertUnwindSafe,
+          sync::mpsc::{Receiver, SyncSender}};
+
+/// Outcome of a handler run, Err if it panicked
+pub(crate) type HandlerOutcome = std::thread::Result<(Result<()>, Vec<Reply>)>;
+
+struct Job
+{
+    on_message: OnMessageFn,
+    message:    String,
+    handle:     ConnectionHandle
+}
+
+struct Runner
+{
+    jobs:    SyncSender<Job>,
+    results: Receiver<HandlerOutcome>
+}
+
+/// Runs message handlers on a thread of their own so the worker can give
+/// up on one that overruns `SockleServerConfig::handler_timeout`
+///
+/// One per worker, shared by its connections. A handler can't be stopped,
+/// so one that overruns is left to finish on the thread it's on, which is
+/// abandoned, and the next message starts a new thread.
+pub(crate) struct Watchdog
+{
+    timeout: Duration,
+    config:  Arc<SockleServerConfig>,
+    runner:  RefCell<Option<Runner>>
+}
+
+impl Watchdog
+{
+    pub(crate) fn new(timeout: Duration, config: Arc<SockleServerConfig>) -> Self
+    {
+        Self { timeout,
+               config,
+               runner: RefCell::new(None) }
+    }
+
+    /// Runs on_message, None if it didn't return within the timeout
+    ///
+    /// Runs it on the calling thread, without a timeout, if no thread can
+    /// be started for it.
+    pub(crate) fn run(&self, on_message: &OnMessageFn, message: String, handle: &ConnectionHandle)
+                      -> Option<HandlerOutcome>
+    {
+        let job = Job { on_message: on_message.clone(),
+                        message,
+                        handle: handle.clone() };
+        let mut runner = self.runner.borrow_mut();
+        if runner.is_none()
+        {
+            match self.spawn()
+            {
+                Ok(r) => *runner = Some(r),
+                Err(e) =>
+                {
+                    log::error!("Unable to start handler thread, running handler without a timeout: {e}");
+                    return Some(Watchdog::call(job));
+                }
+            }
+        }
+        let r = runner.as_ref().unwrap();
+        let outcome = match r.jobs.send(job)
+        {
+            Ok(()) => r.results.recv_timeout(self.timeout).ok(),
+            Err(_) => None
+        };
+        if outcome.is_none()
+        {
+            // Whatever it is still doing, its result is no longer wanted
+            *runner = None;
+        }
+        outcome
+    }
+
+    fn spawn(&self) -> std::io::Result<Runner>
+    {
+        let (jobs, jobs_r) = std::sync::mpsc::sync_channel::<Job>(1);
+        let (results, results_r) = std::sync::mpsc::sync_channel(1);
+        let run = move || {
+            for job in jobs_r
+            {
+                if results.send(Watchdog::call(job)).is_err()
+                {
+                    // Abandoned after overrunning
+                    break;
+                }
+            }
+        };
+        self.config.thread_builder("Handler").spawn(run)?;
+        Ok(Runner { jobs,
+                    results: results_r })
+    }
+
+    fn call(job: Job) -> HandlerOutcome
+    {
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let q = replies.clone();
+        std::panic::catch_unwind(AssertUnwindSafe(|| {
+            job.handle.enter(|| (job.on_message)(job.message, Box::new(move |r| q.lock().unwrap().push(r)), &job.handle))
+        })).map(|result| (result, std::mem::take(&mut *replies.lock().unwrap())))
+    }
+}
@@ -0,0 +1,235 @@
+use std::{collections::VecDeque,
+          io::{Read, Write},
+          net::{SocketAddr, TcpStream},
+          sync::{atomic::{AtomicBool, Ordering},
+                 Arc,
+                 Condvar,
+                 Mutex},
+          time::{Duration, Instant}};
+
+/// Byte stream a server or client connection runs over
+///
+/// Implemented for `TcpStream` and the in-memory `MemoryStream`. Reads in
+/// non-blocking mode or past the read timeout must fail with `WouldBlock`
+/// or `TimedOut`, as sockets do.
+pub trait SockleTransport: Read + Write + Send + 'static
+{
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// Sets TCP_NODELAY, does nothing for transports without it
+    fn set_nodelay(&self, _nodelay: bool) -> std::io::Result<()>
+    {
+        Ok(())
+    }
+
+    /// The TCP stream this is, None for other transports
+    fn as_tcp(&self) -> Option<&TcpStream>
+    {
+        None
+    }
+}
+
+impl SockleTransport for TcpStream
+{
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>
+    {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        TcpStream::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        TcpStream::local_addr(self)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> std::io::Result<()>
+    {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream>
+    {
+        Some(self)
+    }
+}
+
+/// One direction of a `MemoryStream` pair
+#[derive(Default)]
+struct Pipe
+{
+    state: Mutex<PipeState>,
+    ready: Condvar
+}
+
+#[derive(Default)]
+struct PipeState
+{
+    data:   VecDeque<u8>,
+    closed: bool
+}
+
+impl Pipe
+{
+    fn close(&self)
+    {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory duplex stream, see `MemoryStream::pair`
+///
+/// Stands in for a socket in tests: no ports, no OS buffers. Writes never
+/// block, reads honour non-blocking mode and the read timeout. Dropping
+/// one end makes reads on the other return end of file once drained and
+/// writes fail with `BrokenPipe`. Both addresses report 127.0.0.1:0.
+pub struct MemoryStream
+{
+    read:         Arc<Pipe>,
+    write:        Arc<Pipe>,
+    nonblocking:  AtomicBool,
+    read_timeout: Mutex<Option<Duration>>
+}
+
+impl MemoryStream
+{
+    /// Two connected ends, what is written to one is read from the other
+    pub fn pair() -> (MemoryStream, MemoryStream)
+    {
+        let a = Arc::new(Pipe::default());
+        let b = Arc::new(Pipe::default());
+        (MemoryStream::new(a.clone(), b.clone()), MemoryStream::new(b, a))
+    }
+
+    fn new(read: Arc<Pipe>, write: Arc<Pipe>) -> Self
+    {
+        Self { read,
+               write,
+               nonblocking: AtomicBool::new(false),
+               read_timeout: Mutex::new(None) }
+    }
+}
+
+impl Read for MemoryStream
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        let deadline = self.read_timeout.lock().unwrap().map(|t| Instant::now() + t);
+        let mut state = self.read.state.lock().unwrap();
+        loop
+        {
+            if !state.data.is_empty() || buf.is_empty()
+            {
+                let len = buf.len().min(state.data.len());
+                for (b, d) in buf.iter_mut().zip(state.data.drain(..len))
+                {
+                    *b = d;
+                }
+                return Ok(len);
+            }
+            if state.closed
+            {
+                return Ok(0);
+            }
+            if self.nonblocking.load(Ordering::Relaxed)
+            {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            state = match deadline
+            {
+                Some(deadline) =>
+                {
+                    let now = Instant::now();
+                    if now >= deadline
+                    {
+                        return Err(std::io::ErrorKind::WouldBlock.into());
+                    }
+                    self.read.ready.wait_timeout(state, deadline - now).unwrap().0
+                }
+                None => self.read.ready.wait(state).unwrap()
+            };
+        }
+    }
+}
+
+impl Write for MemoryStream
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        let mut state = self.write.state.lock().unwrap();
+        if state.closed
+        {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buf);
+        self.write.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+impl SockleTransport for MemoryStream
+{
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>
+    {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    /// Writes never block, nothing to time out
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()>
+    {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        Ok(SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        Ok(SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+}
+
+impl Drop for MemoryStream
+{
+    fn drop(&mut self)
+    {
+        self.read.close();
+        self.write.close();
+    }
+}